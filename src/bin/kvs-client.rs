@@ -1,14 +1,12 @@
 extern crate clap;
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use std::process::exit;
-use std::env::current_dir;
-use kvs::{KvStore, KvsError, Result};
-use std::path::PathBuf;
-use std::net::TcpStream;
+use kvs::Result;
+use std::net::{Shutdown, TcpStream};
 use std::io::prelude::*;
 
 
-fn valid(address :&String) -> bool {
+fn valid(address :&str) -> bool {
     //检查是否有：，以及ip是合理的，也就是有3个点，并且每个值小于等于255
     let mut colon_number = 0;
     let mut point_number = 0;
@@ -23,16 +21,97 @@ fn valid(address :&String) -> bool {
             if point_number > 4 {
                 return false;
             }
-        } else if !(item >= '0' && item <= '9'){
+        } else if !item.is_ascii_digit() {
             return false;
         }
     }
     
-    if colon_number != 1 || point_number != 3 {
-        return false;
+    colon_number == 1 && point_number == 3
+}
+
+/// 取出 --addr 参数，没有的话用默认地址
+fn address_of(matches: &ArgMatches) -> String {
+    if let Some(address) = matches.value_of("addr") {
+        //这边要加一个判断address是否符合要求
+        if !valid(address) {
+            println!("Please Enter the Corrent Address with IP:Port!");
+            exit(1);
+        }
+        address.to_string()
+    } else {
+        String::from("127.0.0.1:4000")
     }
+}
+
+/// 条件写入的值和预期的对不上时的退出码，和其他错误的 1 区分开
+const CONFLICT_EXIT_CODE: i32 = 3;
+
+/// 发一条 cas 给服务端：有值的写成 "=value"，没有的留空
+fn compare_and_swap(matches: &ArgMatches, key: &str, expected: Option<&str>, new: Option<&str>) -> Result<()> {
+    let field = |value: Option<&str>| value.map_or_else(String::new, |value| format!("={}", value));
+    let address_with_port = address_of(matches);
+    let mut stream = TcpStream::connect(address_with_port).unwrap();
 
-    return true;
+    let input = format!("cas {} {} {}", key, field(expected), field(new));
+    stream.write_all(input.as_bytes()).expect("failed to write");
+    stream.flush()?;
+    stream.shutdown(Shutdown::Write)?; // 服务端读到结尾才开始处理
+
+    let mut buffer = String::new();
+    match stream.read_to_string(&mut buffer) {
+        Ok(_) if buffer.is_empty() => {}
+        Ok(_) if buffer == "Conflict" => {
+            eprintln!("Conflict: the current value of {} is not the expected one", key);
+            exit(CONFLICT_EXIT_CODE);
+        }
+        Ok(_) => {
+            eprintln!("{}", buffer);
+            exit(1);
+        }
+        Err(e) => {
+            println!("Failed to write data: {}", e);
+            exit(1);
+        }
+    }
+    Ok(())
+}
+
+/// 发一条 incr 给服务端，成功的话打印新的值
+fn incr_by(matches: &ArgMatches, delta: i64) -> Result<()> {
+    let key = matches.value_of("KEY").unwrap();
+    let address_with_port = address_of(matches);
+    let mut stream = TcpStream::connect(address_with_port).unwrap();
+
+    let input = format!("incr {} {}", key, delta);
+    stream.write_all(input.as_bytes()).expect("failed to write");
+    stream.flush()?;
+    stream.shutdown(Shutdown::Write)?; // 服务端读到结尾才开始处理
+
+    let mut buffer = String::new();
+    match stream.read_to_string(&mut buffer) {
+        Ok(_) if buffer.starts_with("Incr Failed") => {
+            eprintln!("{}", buffer);
+            exit(1);
+        }
+        Ok(_) => println!("{}", buffer),
+        Err(e) => {
+            println!("Failed to receive data: {}", e);
+            exit(1);
+        }
+    }
+    Ok(())
+}
+
+/// --by 的值，默认是 1
+fn step_of(matches: &ArgMatches) -> i64 {
+    match matches.value_of("by").map(str::parse::<i64>) {
+        None => 1,
+        Some(Ok(step)) => step,
+        Some(Err(_)) => {
+            eprintln!("Step must be an integer");
+            exit(1);
+        }
+    }
 }
 
 fn main() -> Result<()> {
@@ -49,6 +128,7 @@ fn main() -> Result<()> {
                         .help("The string value of the key")
                         .required(true),
                 )
+                .arg(Arg::from_usage("--ttl = <SECONDS> 'remove the key after SECONDS, default is never'").required(false))
                 .arg(Arg::from_usage("-a, --addr = <IP Address With Port> 'set the related address'").required(false)),
         )
         .subcommand(
@@ -63,59 +143,138 @@ fn main() -> Result<()> {
                 .arg(Arg::with_name("KEY").help("A string key").required(true))
                 .arg(Arg::from_usage("-a, --addr = <IP Address With Port> 'set the related address'").required(false)),
         )
+        .subcommand(
+            SubCommand::with_name("ttl")
+                .about("Print how many seconds are left until a key expires, or \"No expiry\". If the key does not exist, print \"Key not found\".")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(Arg::from_usage("-a, --addr = <IP Address With Port> 'set the related address'").required(false)),
+        )
+        .subcommand(
+            SubCommand::with_name("cas")
+                .about("Set KEY to --new, or remove it without --new, only if its value is --expected, or it does not exist without --expected. Exit with code 3 if it is not.")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(Arg::from_usage("--expected = <VALUE> 'the value KEY must have, default is none'").required(false))
+                .arg(Arg::from_usage("--new = <VALUE> 'the value to set, default is to remove KEY'").required(false))
+                .arg(Arg::from_usage("-a, --addr = <IP Address With Port> 'set the related address'").required(false)),
+        )
+        .subcommand(
+            SubCommand::with_name("set-if-absent")
+                .about("Set the value of a key that does not exist yet. Exit with code 3 if it exists.")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(Arg::with_name("VALUE").help("The string value of the key").required(true))
+                .arg(Arg::from_usage("-a, --addr = <IP Address With Port> 'set the related address'").required(false)),
+        )
+        .subcommand(
+            SubCommand::with_name("rm-if-equals")
+                .about("Remove a key only if it has the given value. Exit with code 3 if it does not.")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(Arg::with_name("VALUE").help("The value the key must have").required(true))
+                .arg(Arg::from_usage("-a, --addr = <IP Address With Port> 'set the related address'").required(false)),
+        )
+        .subcommand(
+            SubCommand::with_name("incr")
+                .about("Add to the integer value of a key, 0 if it does not exist, and print the result. Return an error if the value is not an integer.")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(Arg::from_usage("--by = <N> 'how much to add, default is 1'").required(false).allow_hyphen_values(true))
+                .arg(Arg::from_usage("-a, --addr = <IP Address With Port> 'set the related address'").required(false)),
+        )
+        .subcommand(
+            SubCommand::with_name("decr")
+                .about("Subtract from the integer value of a key, 0 if it does not exist, and print the result. Return an error if the value is not an integer.")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(Arg::from_usage("--by = <N> 'how much to subtract, default is 1'").required(false).allow_hyphen_values(true))
+                .arg(Arg::from_usage("-a, --addr = <IP Address With Port> 'set the related address'").required(false)),
+        )
+        .subcommand(
+            SubCommand::with_name("batch")
+                .about("Apply several writes atomically, e.g. `batch set KEY VALUE rm KEY`. Return an error if they are not written.")
+                .arg(
+                    Arg::with_name("OPS")
+                        .help("writes in order: `set KEY VALUE` or `rm KEY`")
+                        .required(true)
+                        .multiple(true),
+                )
+                .arg(Arg::from_usage("-a, --addr = <IP Address With Port> 'set the related address'").required(false)),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("List the versions of a key the server keeps, newest first, one \"SEQ WRITTEN_AT_MS set VALUE\" or \"SEQ WRITTEN_AT_MS rm\" per line.")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(Arg::from_usage("-a, --addr = <IP Address With Port> 'set the related address'").required(false)),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("List the key/value pairs in key order, one \"key value\" per line.")
+                .arg(Arg::from_usage("--from = <KEY> 'the first key to list, default is the smallest'").required(false))
+                .arg(Arg::from_usage("--to = <KEY> 'stop before this key, default is the end'").required(false))
+                .arg(
+                    Arg::from_usage("--prefix = <PREFIX> 'only list keys starting with PREFIX'")
+                        .required(false)
+                        .conflicts_with_all(&["from", "to"]),
+                )
+                .arg(Arg::from_usage("--limit = <N> 'list at most N pairs'").required(false))
+                .arg(Arg::from_usage("-a, --addr = <IP Address With Port> 'set the related address'").required(false)),
+        )
         .get_matches();
 
     match matches.subcommand() {
         ("set", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
             let value = matches.value_of("VALUE").unwrap();
-            let mut address_with_port = String::from("");
-
-            if let Some(address) = matches.value_of("addr") {
-                //这边要加一个判断address是否符合要求
-                if !valid(&address.to_string()) {
-                    println!("Please Enter the Corrent Address with IP:Port!");
-                    exit(1);
-                }
-                address_with_port = address.to_string();
-            } else {
-                address_with_port = String::from("127.0.0.1:4000");
-            }
+            let address_with_port = address_of(matches);
 
             //TODO:这边要加一个没有connect成功的处理。
             let mut stream = TcpStream::connect(address_with_port).unwrap();
             //println!("Connected to the server!");
 
-            let input = String::from("set") + " " + key + " " + value; //统一用空格隔开
+            //统一用空格隔开，带 TTL 的用 setex，免得和值混在一起
+            let input = match matches.value_of("ttl") {
+                // 协议里用毫秒
+                Some(ttl) => match ttl.parse::<u64>().ok().and_then(|ttl| ttl.checked_mul(1000)) {
+                    Some(ttl) => format!("setex {} {} {}", ttl, key, value),
+                    None => {
+                        eprintln!("TTL must be a number of seconds");
+                        exit(1);
+                    }
+                },
+                None => String::from("set") + " " + key + " " + value,
+            };
 
-            stream.write(input.as_bytes()).expect("failed to write");
+            stream.write_all(input.as_bytes()).expect("failed to write");
+            stream.flush()?;
+            stream.shutdown(Shutdown::Write)?; // 服务端读到结尾才开始处理
             //println!("Send input {}", input);
 
+            // 服务端写好以后才会关掉连接，有内容说明出错了
+            let mut buffer = String::new();
+            match stream.read_to_string(&mut buffer) {
+                Ok(_) => {
+                    if !buffer.is_empty() {
+                        eprintln!("{}", buffer);
+                        exit(1);
+                    }
+                }
+                Err(e) => {
+                    println!("Failed to set data: {}", e);
+                    exit(1);
+                }
+            }
+
             Ok(())
         }
         ("get", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
             
-            let mut address_with_port = String::from("");
-
-            if let Some(address) = matches.value_of("addr") {
-                //这边要加一个判断address是否符合要求
-                if !valid(&address.to_string()) {
-                    println!("Please Enter the Corrent Address with IP:Port!");
-                    exit(1);
-                }
-                address_with_port = address.to_string();
-            } else {
-                address_with_port = String::from("127.0.0.1:4000");
-            }
+            let address_with_port = address_of(matches);
 
             let mut stream = TcpStream::connect(address_with_port).unwrap();
             //println!("Connected to the server!");
 
             let input = String::from("get") + " " + key;
 
-            stream.write(input.as_bytes()).expect("failed to write");
+            stream.write_all(input.as_bytes()).expect("failed to write");
             stream.flush()?;
+            stream.shutdown(Shutdown::Write)?; // 服务端读到结尾才开始处理
 
             //println!("Send input {}", input);
             let mut buffer = String::new();
@@ -134,26 +293,16 @@ fn main() -> Result<()> {
         ("rm", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
 
-            let mut address_with_port = String::from("");
-
-            if let Some(address) = matches.value_of("addr") {
-                //这边要加一个判断address是否符合要求
-                if !valid(&address.to_string()) {
-                    println!("Please Enter the Corrent Address with IP:Port!");
-                    exit(1);
-                }
-                address_with_port = address.to_string();
-            } else {
-                address_with_port = String::from("127.0.0.1:4000");
-            }
+            let address_with_port = address_of(matches);
 
             let mut stream = TcpStream::connect(address_with_port).unwrap();
             //println!("Connected to the server!");
 
             let input = String::from("rm") + " " + key;
 
-            stream.write(input.as_bytes()).expect("failed to write");
+            stream.write_all(input.as_bytes()).expect("failed to write");
             stream.flush()?;
+            stream.shutdown(Shutdown::Write)?; // 服务端读到结尾才开始处理
 
             let mut buffer = String::new();
             match stream.read_to_string(&mut buffer) {
@@ -175,6 +324,147 @@ fn main() -> Result<()> {
             Ok(())
 
             
+        }
+        ("ttl", Some(matches)) => {
+            let key = matches.value_of("KEY").unwrap();
+            let address_with_port = address_of(matches);
+            let mut stream = TcpStream::connect(address_with_port).unwrap();
+
+            let input = format!("ttl {}", key);
+            stream.write_all(input.as_bytes()).expect("failed to write");
+            stream.flush()?;
+            stream.shutdown(Shutdown::Write)?; // 服务端读到结尾才开始处理
+
+            // 服务端回的是毫秒，不足一秒的按一秒算
+            let mut buffer = String::new();
+            match stream.read_to_string(&mut buffer) {
+                Ok(_) if buffer == "none" => println!("No expiry"),
+                Ok(_) => match buffer.parse::<u64>() {
+                    Ok(ms) => println!("{}", ms.div_ceil(1000)),
+                    Err(_) if buffer == "Key not found" => println!("{}", buffer),
+                    Err(_) => {
+                        eprintln!("{}", buffer);
+                        exit(1);
+                    }
+                },
+                Err(e) => {
+                    println!("Failed to receive data: {}", e);
+                    exit(1);
+                }
+            }
+            Ok(())
+        }
+        ("cas", Some(matches)) => {
+            let key = matches.value_of("KEY").unwrap();
+            compare_and_swap(matches, key, matches.value_of("expected"), matches.value_of("new"))
+        }
+        ("set-if-absent", Some(matches)) => {
+            let key = matches.value_of("KEY").unwrap();
+            compare_and_swap(matches, key, None, matches.value_of("VALUE"))
+        }
+        ("rm-if-equals", Some(matches)) => {
+            let key = matches.value_of("KEY").unwrap();
+            compare_and_swap(matches, key, matches.value_of("VALUE"), None)
+        }
+        ("incr", Some(matches)) => incr_by(matches, step_of(matches)),
+        ("decr", Some(matches)) => match step_of(matches).checked_neg() {
+            Some(delta) => incr_by(matches, delta),
+            None => {
+                eprintln!("Step is out of range");
+                exit(1);
+            }
+        },
+        ("batch", Some(matches)) => {
+            let ops: Vec<&str> = matches.values_of("OPS").unwrap().collect();
+            let address_with_port = address_of(matches);
+            let mut stream = TcpStream::connect(address_with_port).unwrap();
+
+            let input = format!("batch {}", ops.join(" "));
+            stream.write_all(input.as_bytes()).expect("failed to write");
+            stream.flush()?;
+            stream.shutdown(Shutdown::Write)?; // 服务端读到结尾才开始处理
+
+            // 和 set 一样，成功的话什么都不回
+            let mut buffer = String::new();
+            match stream.read_to_string(&mut buffer) {
+                Ok(_) => {
+                    if !buffer.is_empty() {
+                        eprintln!("{}", buffer);
+                        exit(1);
+                    }
+                }
+                Err(e) => {
+                    println!("Failed to write batch: {}", e);
+                    exit(1);
+                }
+            }
+
+            Ok(())
+        }
+        ("history", Some(matches)) => {
+            let key = matches.value_of("KEY").unwrap();
+            let address_with_port = address_of(matches);
+            let mut stream = TcpStream::connect(address_with_port).unwrap();
+
+            let input = format!("history {}", key);
+            stream.write_all(input.as_bytes()).expect("failed to write");
+            stream.flush()?;
+            stream.shutdown(Shutdown::Write)?; // 服务端读到结尾才开始处理
+
+            let mut buffer = String::new();
+            match stream.read_to_string(&mut buffer) {
+                Ok(_) if buffer.starts_with("History Failed") => {
+                    eprintln!("{}", buffer);
+                    exit(1);
+                }
+                Ok(_) => print!("{}", buffer),
+                Err(e) => {
+                    println!("Failed to receive data: {}", e);
+                    exit(1);
+                }
+            }
+            Ok(())
+        }
+        ("scan", Some(matches)) => {
+            let limit = match matches.value_of("limit").map(str::parse::<usize>) {
+                None => usize::MAX,
+                Some(Ok(limit)) => limit,
+                Some(Err(_)) => {
+                    eprintln!("Limit must be a number");
+                    exit(1);
+                }
+            };
+
+            let address_with_port = address_of(matches);
+            let mut stream = TcpStream::connect(address_with_port).unwrap();
+
+            // 没给 from/to 就发空的，服务端当成不限
+            let input = match matches.value_of("prefix") {
+                Some(prefix) => format!("scan_prefix {} {}", limit, prefix),
+                None => format!(
+                    "scan {} {} {}",
+                    limit,
+                    matches.value_of("from").unwrap_or(""),
+                    matches.value_of("to").unwrap_or("")
+                ),
+            };
+
+            stream.write_all(input.as_bytes()).expect("failed to write");
+            stream.flush()?;
+            stream.shutdown(Shutdown::Write)?; // 服务端读到结尾才开始处理
+
+            let mut buffer = String::new();
+            match stream.read_to_string(&mut buffer) {
+                Ok(_) => {
+                    print!("{}", buffer);
+                }
+                Err(e) => {
+                    println!("Failed to receive data: {}", e);
+                    exit(1);
+                }
+            }
+
+            Ok(())
         }
         _ => unreachable!(),
     }
@@ -1,18 +1,23 @@
 extern crate clap;
 use clap::{App, Arg};
-use kvs::{KvStore, KvsError, Result, KvsEngine};
-use std::net::TcpListener;
+use kvs::{KvStore, KvStoreOptions, KvsError, Result, KvsEngine, SyncPolicy, VersionRetention, WriteBatch};
+use std::net::{Shutdown, TcpListener, TcpStream};
 use std::process::exit;
 use std::io::prelude::*; // 这玩意到底是啥玩意
 use std::env::current_dir;
+use std::fs::{self, OpenOptions};
+use std::path::{Component, Path, PathBuf};
+use fs2::FileExt;
+use std::ops::Bound;
+use std::time::{Duration, UNIX_EPOCH};
 extern crate env_logger;
 use log::error;
 
-use env_logger::{Builder, Target};
+use env_logger::Builder;
 
 use kvs::thread_pool::*;
 
-fn valid(address :&String) -> bool {
+fn valid(address :&str) -> bool {
     //检查是否有：，以及ip是合理的，也就是有3个点，并且每个值小于等于255
     let mut colon_number = 0;
     let mut point_number = 0;
@@ -27,16 +32,161 @@ fn valid(address :&String) -> bool {
             if point_number > 4 {
                 return false;
             }
-        } else if !(item >= '0' && item <= '9'){
+        } else if !item.is_ascii_digit() {
             return false;
         }
     }
     
-    if colon_number != 1 || point_number != 3 {
-        return false;
+    colon_number == 1 && point_number == 3
+}
+
+/// scan <limit> <from> <to> 或者 scan_prefix <limit> <prefix>，from/to 是空的表示不限，
+/// 找到的每一对写成一行 "key value"
+fn scan(store: &KvStore, command_vec: &[&str]) -> Result<String> {
+    let limit = command_vec
+        .get(1)
+        .and_then(|limit| limit.parse::<usize>().ok())
+        .ok_or_else(|| KvsError::InvalidArgument(String::from("invalid scan limit")))?;
+    let pairs = match command_vec {
+        ["scan", _, from, to] => {
+            let from = if from.is_empty() { Bound::Unbounded } else { Bound::Included(from.to_string()) };
+            let to = if to.is_empty() { Bound::Unbounded } else { Bound::Excluded(to.to_string()) };
+            store.scan((from, to), limit)?
+        }
+        ["scan_prefix", _, prefix] => store.scan_prefix(prefix)?,
+        _ => return Err(KvsError::InvalidArgument(format!("error command {}", command_vec.join(" ")))),
+    };
+
+    let mut reply = String::new();
+    for pair in pairs.take(limit) {
+        let (key, value) = pair?;
+        reply += &format!("{} {}\n", key, value);
     }
+    Ok(reply)
+}
 
-    return true;
+/// history <key>：每个版本一行，从新到旧，"<seq> <写入时间毫秒> set <value>" 或者 "<seq> <写入时间毫秒> rm"，
+/// 不知道写入时间的写成 -
+fn history(store: &KvStore, command_vec: &[&str]) -> Result<String> {
+    let key = match command_vec {
+        ["history", key] => key,
+        _ => return Err(KvsError::InvalidArgument(format!("error command {}", command_vec.join(" ")))),
+    };
+    let mut reply = String::new();
+    for version in store.history(key.to_string())? {
+        let written_at = version
+            .written_at
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or_else(|| String::from("-"), |time| time.as_millis().to_string());
+        match version.value {
+            Some(value) => reply += &format!("{} {} set {}\n", version.seq, written_at, value),
+            None => reply += &format!("{} {} rm\n", version.seq, written_at),
+        }
+    }
+    Ok(reply)
+}
+
+/// 一个请求最长多少字节，客户端发完会关掉写的那一半，读到结尾就是整个请求
+const MAX_REQUEST_LEN: u64 = 1 << 20;
+
+/// batch set <key> <value> rm <key> ...，按顺序放进一个 WriteBatch
+fn parse_batch(command_vec: &[&str]) -> Result<WriteBatch> {
+    let invalid = || KvsError::InvalidArgument(format!("error command {}", command_vec.join(" ")));
+    let mut batch = WriteBatch::new();
+    let mut ops = command_vec[1..].iter();
+    while let Some(op) = ops.next() {
+        match *op {
+            "set" => {
+                let key = ops.next().ok_or_else(invalid)?;
+                let value = ops.next().ok_or_else(invalid)?;
+                batch.set(key.to_string(), value.to_string());
+            }
+            "rm" => {
+                let key = ops.next().ok_or_else(invalid)?;
+                batch.remove(key.to_string());
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    if batch.is_empty() {
+        return Err(invalid());
+    }
+    Ok(batch)
+}
+
+/// cas <key> <expected> <new>：有值的写成 "=value"，空的表示没有这个 key，
+/// 这样空字符串的值也能表示出来
+fn compare_and_swap(store: &KvStore, command_vec: &[&str]) -> Result<()> {
+    let invalid = || KvsError::InvalidArgument(format!("error command {}", command_vec.join(" ")));
+    let value_of = |field: &str| match field {
+        "" => Ok(None),
+        field => field.strip_prefix('=').map(|value| Some(value.to_string())).ok_or_else(invalid),
+    };
+    match command_vec {
+        ["cas", key, expected, new] => store.compare_and_swap(key.to_string(), value_of(expected)?, value_of(new)?),
+        _ => Err(invalid()),
+    }
+}
+
+/*
+--backup-to <dir>：把当前目录的 store 备份到 --backup-root 下面的 dir 然后退出。
+不管有没有 server 在跑，dir 都按同一个规则算，出了 backup root 的一律不行。
+没有 server 在跑的话自己只读打开备份，备份的时候拿着目录锁，免得中间有 server 起来改文件；
+目录被正在跑的 server 占着的话，让 addr 上的那个 server 边服务边备份，它还会再按自己的 --backup-root 查一遍
+*/
+fn backup(dest: &str, backup_root: &str, address_with_port: &str) -> Result<()> {
+    let dir = current_dir()?;
+    // 打开过的 store 都有 MANIFEST 和 LOCK，没有的话这里就不是一个 store
+    if !dir.join("MANIFEST").exists() {
+        return Err(KvsError::InvalidArgument(format!("no store in {}", dir.display())));
+    }
+    let dest = backup_dest(Some(&resolve_backup_root(backup_root)?), dest)?;
+    let lock = OpenOptions::new().write(true).open(dir.join("LOCK"))?;
+    match lock.try_lock_exclusive() {
+        Ok(()) => return KvStore::open_read_only(&dir)?.checkpoint(dest),
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut stream = TcpStream::connect(address_with_port)?;
+    stream.write_all(format!("backup {}", dest.display()).as_bytes())?;
+    stream.flush()?;
+    stream.shutdown(Shutdown::Write)?; // 服务端读到结尾才开始处理
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    if reply.is_empty() {
+        Ok(())
+    } else {
+        Err(KvsError::InvalidArgument(reply.trim_start_matches("Backup Failed: ").to_string()))
+    }
+}
+
+/// --backup-root 按当前目录算，先建好再取规范的绝对路径，判断备份目录在不在它下面才靠得住
+fn resolve_backup_root(root: &str) -> Result<PathBuf> {
+    let root = current_dir()?.join(root);
+    fs::create_dir_all(&root)?;
+    Ok(root.canonicalize()?)
+}
+
+/// 备份目录只能在 backup root 下面，相对路径按 root 算，不然谁连上来都能让 server 往任意地方写
+fn backup_dest(backup_root: Option<&Path>, dest: &str) -> Result<PathBuf> {
+    let root = backup_root.ok_or_else(|| {
+        KvsError::InvalidArgument(String::from("backups are disabled, start the server with --backup-root"))
+    })?;
+    let path = root.join(dest);
+    match path.strip_prefix(root) {
+        Ok(rest)
+            if !rest.as_os_str().is_empty()
+                && rest.components().all(|part| matches!(part, Component::Normal(_))) =>
+        {
+            Ok(path)
+        }
+        _ => Err(KvsError::InvalidArgument(format!(
+            "{} is not inside the backup root {}",
+            dest,
+            root.display()
+        ))),
+    }
 }
 
 fn main() -> Result<()> {
@@ -49,6 +199,10 @@ fn main() -> Result<()> {
         .author(env!("CARGO_PKG_AUTHORS"))
         .arg(Arg::from_usage("-e, --engine = <kvs/sled> 'choose one engine, default is kvs'").required(false))
         .arg(Arg::from_usage("-a, --addr = <IP Address With Port> 'set the related address'").required(false))
+        .arg(Arg::from_usage("-s, --sync = <never/always/every:N/interval:MS> 'when writes are synced to disk, default is never'").required(false))
+        .arg(Arg::from_usage("--retain = <latest/versions:N/window:SECONDS> 'which older versions of every key to keep, default is latest'").required(false))
+        .arg(Arg::from_usage("--backup-root = <DIR> 'allow clients to back up the store into directories under DIR'").required(false))
+        .arg(Arg::from_usage("--backup-to = <DIR> 'copy the store into an empty DIR under --backup-root and exit, through the server at --addr if one is running'").required(false).requires("backup-root"))
         .get_matches();
 
     let mut engine_selection = String::from("kvs"); 
@@ -61,19 +215,60 @@ fn main() -> Result<()> {
         }
     }
     
-    let mut address_with_port = String::from("");
-
-    if let Some(address) = matches.value_of("addr") {
+    let address_with_port = if let Some(address) = matches.value_of("addr") {
         //这边要加一个判断address是否符合要求
-        if !valid(&address.to_string()) {
+        if !valid(address) {
             println!("Please Enter the Corrent Address with IP:Port!");
             exit(1);
         }
-        address_with_port = address.to_string();
+        address.to_string()
     } else {
-        address_with_port = String::from("127.0.0.1:4000");
-    }
+        String::from("127.0.0.1:4000")
+    };
     
+    let sync = match matches.value_of("sync").map(str::parse::<SyncPolicy>) {
+        None => SyncPolicy::Never,
+        Some(Ok(sync)) => sync,
+        Some(Err(e)) => {
+            println!("{}", e);
+            exit(1);
+        }
+    };
+    let retention = match matches.value_of("retain").map(str::parse::<VersionRetention>) {
+        None => VersionRetention::Latest,
+        Some(Ok(retention)) => retention,
+        Some(Err(e)) => {
+            println!("{}", e);
+            exit(1);
+        }
+    };
+    let options = KvStoreOptions::new().sync(sync).retention(retention);
+    if let Some(dest) = matches.value_of("backup-to") {
+        if let Err(e) = backup(dest, matches.value_of("backup-root").unwrap(), &address_with_port) {
+            println!("Backup Failed: {}", e);
+            exit(1);
+        }
+        return Ok(());
+    }
+
+    let backup_root = match matches.value_of("backup-root").map(resolve_backup_root) {
+        None => None,
+        Some(Ok(root)) => Some(root),
+        Some(Err(e)) => {
+            println!("{}", e);
+            exit(1);
+        }
+    };
+
+    // 整个进程只打开一次，目录被别的 server 占着的话直接退出
+    let store = match KvStore::open_with(current_dir()?, options) {
+        Ok(store) => store,
+        Err(e) => {
+            println!("{}", e);
+            exit(1);
+        }
+    };
+
     error!("version is {}, ip with port address is {}, engine is {}", env!("CARGO_PKG_VERSION"), address_with_port, engine_selection);
 
     let listener = TcpListener::bind(address_with_port).expect("Failed and bind with the sender");
@@ -81,32 +276,39 @@ fn main() -> Result<()> {
     let pool =  SharedQueueThreadPool::new(16)?;
 
     for stream in listener.incoming() {
+        let store = store.clone();
+        let backup_root = backup_root.clone();
         pool.spawn(move || match stream {
             Ok(mut stream) => {
-                let mut buffer = [0u8; 100]; // 这边我们设定传输的长度不会超过100
+                let mut buffer = Vec::new();
                 //println!("connection!");
-                match stream.read(&mut buffer) {
+                match (&mut stream).take(MAX_REQUEST_LEN).read_to_end(&mut buffer) {
                     Ok(_) => {
-                        let buffer = String::from_utf8(buffer.to_vec()).unwrap();
-                        let buffer = String::from(buffer.trim_end_matches(char::from(0)));
+                        let buffer = String::from_utf8(buffer).unwrap();
                         let command_vec: Vec<&str> = buffer.split(" ").collect();
 
-                        let mut store = KvStore::open(current_dir().unwrap()).unwrap();
                         // let mut sled_kv = SledKvsEngine::open(current_dir()?)?;
                         // let store:&mut dyn KvsEngine + 'static = kv_store;
 
                         match command_vec[0] {
-                            "set" => {
-                                if command_vec.len() != 3 {
-                                    println!("error command {}", buffer);
-                                } else {
-                                    match store.set(command_vec[1].to_string(), command_vec[2].to_string()) {
-                                        Ok(()) => {
-                                            ()
-                                        }
-                                        Err(_) => {
-                                            println!("Set Failed!");
-                                        }
+                            "set" | "setex" => {
+                                // set <key> <value>
+                                // setex <ttl 毫秒> <key> <value>
+                                // 值里面有空格的话字段数就对不上，直接报错，不能截断以后当成别的参数
+                                // set 返回的时候已经按照 sync 的设定落盘了，这时候关掉连接才算确认
+                                let result = match command_vec[..] {
+                                    ["set", key, value] => store.set(key.to_string(), value.to_string()),
+                                    ["setex", ttl, key, value] => match ttl.parse::<u64>() {
+                                        Ok(ttl) => store.set_with_ttl(key.to_string(), value.to_string(), Duration::from_millis(ttl)),
+                                        Err(_) => Err(KvsError::InvalidArgument(format!("invalid ttl {}", ttl))),
+                                    },
+                                    _ => Err(KvsError::InvalidArgument(format!("error command {}", buffer))),
+                                };
+                                match result {
+                                    Ok(()) => {}
+                                    Err(e) => {
+                                        stream.write_all(format!("Set Failed: {}", e).as_bytes()).expect("failed to write");
+                                        println!("Set Failed!");
                                     }
                                 }
                             }
@@ -115,11 +317,9 @@ fn main() -> Result<()> {
                                     println!("error command {}", buffer);
                                 } else {
                                     match store.remove(command_vec[1].to_string()) {
-                                        Ok(()) => {
-                                            ()
-                                        }
+                                        Ok(()) => {}
                                         Err(KvsError::KeyNotFound) => {
-                                            stream.write("Key not found".as_bytes()).expect("failed to write");
+                                            stream.write_all("Key not found".as_bytes()).expect("failed to write");
                                             println!("Remove Error: Key not found");
                                         }
                                         Err(_) => {
@@ -135,10 +335,10 @@ fn main() -> Result<()> {
                                 } else {
                                     match store.get(command_vec[1].to_string()) {
                                         Ok(Some(value)) => {
-                                            stream.write(value.as_bytes()).expect("failed to write");
+                                            stream.write_all(value.as_bytes()).expect("failed to write");
                                         }
                                         Ok(None) => {
-                                            stream.write("Key not found".as_bytes()).expect("failed to write");
+                                            stream.write_all("Key not found".as_bytes()).expect("failed to write");
                                             println!("Get Error: Key not found");
                                         }
                                         Err(_) => {
@@ -147,6 +347,65 @@ fn main() -> Result<()> {
                                     }
                                 }
                             }
+                            "ttl" => {
+                                // 回剩下的毫秒数，没有过期时间回 none
+                                let reply = match command_vec.as_slice() {
+                                    ["ttl", key] => match store.ttl(key.to_string()) {
+                                        Ok(Some(ttl)) => ttl.as_millis().to_string(),
+                                        Ok(None) => String::from("none"),
+                                        Err(KvsError::KeyNotFound) => String::from("Key not found"),
+                                        Err(e) => format!("TTL Failed: {}", e),
+                                    },
+                                    _ => format!("TTL Failed: error command {}", buffer),
+                                };
+                                stream.write_all(reply.as_bytes()).expect("failed to write");
+                            }
+                            "batch" => {
+                                // 和 set 一样，成功就什么都不回
+                                if let Err(e) = parse_batch(&command_vec).and_then(|batch| store.write_batch(batch)) {
+                                    stream.write_all(format!("Batch Failed: {}", e).as_bytes()).expect("failed to write");
+                                }
+                            }
+                            "cas" => {
+                                // 成功什么都不回，值对不上回 Conflict，客户端靠它区分退出码
+                                match compare_and_swap(&store, &command_vec) {
+                                    Ok(()) => {}
+                                    Err(KvsError::Conflict) => {
+                                        stream.write_all(b"Conflict").expect("failed to write");
+                                    }
+                                    Err(e) => {
+                                        stream.write_all(format!("CAS Failed: {}", e).as_bytes()).expect("failed to write");
+                                    }
+                                }
+                            }
+                            "incr" => {
+                                // 回新的值；出错的话回 "Incr Failed: ..."
+                                let reply = match command_vec.as_slice() {
+                                    ["incr", key, delta] => match delta.parse::<i64>() {
+                                        Ok(delta) => store.incr_by(key.to_string(), delta).map(|value| value.to_string()),
+                                        Err(_) => Err(KvsError::InvalidArgument(format!("invalid delta {}", delta))),
+                                    },
+                                    _ => Err(KvsError::InvalidArgument(format!("error command {}", buffer))),
+                                };
+                                let reply = reply.unwrap_or_else(|e| format!("Incr Failed: {}", e));
+                                stream.write_all(reply.as_bytes()).expect("failed to write");
+                            }
+                            "history" => {
+                                let reply = history(&store, &command_vec).unwrap_or_else(|e| format!("History Failed: {}", e));
+                                stream.write_all(reply.as_bytes()).expect("failed to write");
+                            }
+                            "backup" => {
+                                // backup <目录>，目录里可能有空格；成功什么都不回
+                                let dest = buffer.strip_prefix("backup ").unwrap_or_default();
+                                let result = backup_dest(backup_root.as_deref(), dest).and_then(|dest| store.checkpoint(dest));
+                                if let Err(e) = result {
+                                    stream.write_all(format!("Backup Failed: {}", e).as_bytes()).expect("failed to write");
+                                }
+                            }
+                            "scan" | "scan_prefix" => {
+                                let reply = scan(&store, &command_vec).unwrap_or_else(|e| format!("Scan Failed: {}", e));
+                                stream.write_all(reply.as_bytes()).expect("failed to write");
+                            }
                             _ => {
                                 println!("error command {}", buffer);
                             }
@@ -0,0 +1,115 @@
+//! Bloom filters over the keys of an sstable.
+//!
+//! A filter answers "definitely not here" or "maybe here" for a key, so a lookup
+//! for an absent key can skip a table without reading any of its blocks.
+//!
+//! ```text
+//! | probes: u8 | bit array |
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Probe counts above this buy nothing at any sensible bits per key.
+const MAX_PROBES: u32 = 30;
+
+/// The filter of one sstable.
+#[derive(Debug)]
+pub(crate) struct BloomFilter {
+    probes: u32,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    /// Build a filter of about `bits_per_key` bits for every key hashed with `hash`.
+    pub fn build(hashes: &[u64], bits_per_key: u32) -> BloomFilter {
+        // 探测次数取 bits_per_key * ln2 时误判率最低
+        let probes = ((bits_per_key as f64 * 0.69) as u32).clamp(1, MAX_PROBES);
+        let len = ((hashes.len() as u64 * bits_per_key as u64).max(64) as usize).div_ceil(8);
+        let mut filter = BloomFilter { probes, bits: vec![0u8; len] };
+        for hash in hashes {
+            for bit in filter.bit_positions(*hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    /// Whether the key with this hash may be in the table.
+    pub fn may_contain(&self, hash: u64) -> bool {
+        self.bit_positions(hash).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(1 + self.bits.len());
+        buffer.push(self.probes as u8);
+        buffer.extend_from_slice(&self.bits);
+        buffer
+    }
+
+    /// `None` if `buffer` is not a filter this release can use.
+    pub fn decode(buffer: &[u8]) -> Option<BloomFilter> {
+        let (&probes, bits) = buffer.split_first()?;
+        let probes = probes as u32;
+        if probes == 0 || probes > MAX_PROBES || bits.is_empty() {
+            return None;
+        }
+        Some(BloomFilter { probes, bits: bits.to_vec() })
+    }
+
+    /// 双重哈希：用一个 64 位的哈希值拼出所有探测位置
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 8;
+        let delta = hash.rotate_left(32) | 1;
+        (0..self.probes as u64).map(move |i| (hash.wrapping_add(i.wrapping_mul(delta)) % len) as usize)
+    }
+}
+
+/// The hash the filters are built from. It is part of the file format, so it must
+/// never change: 64 bit FNV-1a.
+pub(crate) fn hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// How well the Bloom filters are doing, as returned by `KvStore::bloom_stats`.
+///
+/// The counts cover sstable lookups since the store was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BloomStats {
+    /// Lookups that asked a filter whether to read a table.
+    pub checks: u64,
+    /// Table reads skipped because the filter ruled the key out.
+    pub reads_avoided: u64,
+    /// Table reads the filter let through that found nothing.
+    pub false_positives: u64,
+}
+
+/// 所有 clone 出来的 KvStore 共用的计数
+#[derive(Debug, Default)]
+pub(crate) struct BloomCounters {
+    checks: AtomicU64,
+    reads_avoided: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+impl BloomCounters {
+    pub fn checked(&self, may_contain: bool) {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        if !may_contain {
+            self.reads_avoided.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn false_positive(&self) {
+        self.false_positives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> BloomStats {
+        BloomStats {
+            checks: self.checks.load(Ordering::Relaxed),
+            reads_avoided: self.reads_avoided.load(Ordering::Relaxed),
+            false_positives: self.false_positives.load(Ordering::Relaxed),
+        }
+    }
+}
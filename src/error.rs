@@ -1,5 +1,9 @@
+// `failure`'s derive expands into impls nested in a `const _` block.
+#![allow(non_local_definitions)]
+
 use failure::Fail;
 use std::io;
+use std::string::FromUtf8Error;
 
 /// Error type for kvs.
 #[derive(Fail, Debug)]
@@ -10,6 +14,39 @@ pub enum KvsError {
     /// Serialization or deserialization error.
     #[fail(display = "{}", _0)]
     SerdeError(#[cause] serde_json::Error),
+    /// A key or value read from disk is not valid UTF-8.
+    #[fail(display = "{}", _0)]
+    Utf8Error(#[cause] FromUtf8Error),
+    /// A data file is not in a format this version understands.
+    #[fail(display = "Unsupported file format: {}", _0)]
+    UnsupportedFormat(String),
+    /// A record failed its checksum somewhere other than the end of a file.
+    #[fail(display = "Corrupted record in {} at offset {}", file, offset)]
+    Corruption {
+        /// Path of the damaged file.
+        file: String,
+        /// Offset of the damaged record within the file.
+        offset: u64,
+    },
+    /// A data file the manifest lists is gone.
+    #[fail(display = "Data file is missing: {}", _0)]
+    MissingFile(String),
+    /// Another process has the store open for writing.
+    #[fail(display = "Store directory is in use by another process: {}", _0)]
+    Locked(String),
+    /// The store was opened read-only and cannot be written.
+    #[fail(display = "Store is opened read-only")]
+    ReadOnly,
+    /// A setting or argument could not be understood.
+    #[fail(display = "{}", _0)]
+    InvalidArgument(String),
+    /// A conditional write found a different value than it expected, or a transaction
+    /// kept conflicting with other writes.
+    #[fail(display = "Value does not match the expected one")]
+    Conflict,
+    /// A counter operation found a value that is not an integer.
+    #[fail(display = "Value is not an integer: {}", _0)]
+    NotANumber(String),
     /// Not found the Key
     #[fail(display = "Key not found")]
     KeyNotFound,
@@ -30,5 +67,11 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<FromUtf8Error> for KvsError {
+    fn from(err: FromUtf8Error) -> KvsError {
+        KvsError::Utf8Error(err)
+    }
+}
+
 /// Result type for kvs.
-pub type Result<T> = std::result::Result<T, KvsError>;
\ No newline at end of file
+pub type Result<T> = std::result::Result<T, KvsError>;
@@ -0,0 +1,30 @@
+//! The seam between the store and the files its log is appended to.
+
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// A log file open for appending.
+///
+/// The store reads the log back through handles of its own, so whatever was written
+/// must be in the file once `flush` returns.
+pub trait LogFile: Write + Send + Debug {
+    /// Flush everything written so far to stable storage.
+    fn sync_data(&mut self) -> io::Result<()>;
+}
+
+impl LogFile for File {
+    fn sync_data(&mut self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+/// Wraps every log file the store writes to.
+///
+/// The default passes the `File` through untouched. Tests plug in their own layer to
+/// see which writes were synced, or to make writes fail.
+pub trait FileLayer: Send + Sync + Debug {
+    /// Wrap `file`, freshly opened for appending at `path`.
+    fn open_log(&self, path: &Path, file: File) -> io::Result<Box<dyn LogFile>>;
+}
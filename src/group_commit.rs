@@ -0,0 +1,80 @@
+//! Batching concurrent writes into one append and one sync.
+
+use super::{KvsError, Result};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Condvar, Mutex};
+
+/// Writers queue their items here. The first writer to find no commit in progress
+/// becomes the leader: it takes everything queued so far and writes it as one batch,
+/// while the others wait to be told how their batch went.
+#[derive(Debug)]
+pub(crate) struct GroupCommit<T> {
+    state: Mutex<State<T>>,
+    committed: Condvar,
+}
+
+#[derive(Debug)]
+struct State<T> {
+    queue: Vec<(u64, T)>,
+    next_ticket: u64,
+    leader: bool,
+    // Outcome for writers whose items were committed by another leader
+    done: HashMap<u64, std::result::Result<(), (io::ErrorKind, String)>>,
+}
+
+impl<T> Default for GroupCommit<T> {
+    fn default() -> GroupCommit<T> {
+        GroupCommit {
+            state: Mutex::new(State {
+                queue: Vec::new(),
+                next_ticket: 0,
+                leader: false,
+                done: HashMap::new(),
+            }),
+            committed: Condvar::new(),
+        }
+    }
+}
+
+impl<T> GroupCommit<T> {
+    /// Commit `item`, possibly together with items queued by other threads.
+    ///
+    /// Returns once `write` has been called on a batch containing `item`. A follower
+    /// sees the leader's error as an `IoError` carrying the same message.
+    pub fn commit(&self, item: T, write: impl FnOnce(Vec<T>) -> Result<()>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.queue.push((ticket, item));
+
+        loop {
+            if let Some(result) = state.done.remove(&ticket) {
+                return result.map_err(|(kind, message)| io::Error::new(kind, message).into());
+            }
+            if !state.leader {
+                break;
+            }
+            state = self.committed.wait(state).unwrap();
+        }
+
+        // 没有人在写，我们的这条一定还在队列里，连同别人排着的一起写掉
+        state.leader = true;
+        let (tickets, batch): (Vec<u64>, Vec<T>) = std::mem::take(&mut state.queue).into_iter().unzip();
+        drop(state);
+
+        let result = write(batch);
+
+        let mut state = self.state.lock().unwrap();
+        state.leader = false;
+        let shared = result.as_ref().map(|_| ()).map_err(|e| match e {
+            KvsError::IoError(e) => (e.kind(), e.to_string()),
+            e => (io::ErrorKind::Other, e.to_string()),
+        });
+        for follower in tickets.into_iter().filter(|t| *t != ticket) {
+            state.done.insert(follower, shared.clone());
+        }
+        self.committed.notify_all();
+        result
+    }
+}
@@ -0,0 +1,145 @@
+//! Hint files: the keys of a sealed log and where their records are, so that opening
+//! the store can rebuild the index without reading the log itself.
+//!
+//! `log_<gen>.hint` describes the first `log length` bytes of `log_<gen>.txt`:
+//!
+//! ```text
+//! | magic "KVSH" | version: u32 | log length: u64 | entry | entry | ... | crc32: u32 |
+//! entry: | type: u8 | key length: u32 | record begin: u64 | record end: u64 | seq: u64 | key |
+//! ```
+//!
+//! Version 1 hints have no sequence numbers; their records predate them and have 0.
+//!
+//! The newest record of every key is listed, preceded by the older records of the key
+//! that the store keeps under its `VersionRetention`, oldest first. A hint is written
+//! whenever a log is sealed. One that is missing, damaged or longer than its log is
+//! ignored and the log is read instead.
+
+use super::record::RecordType;
+use super::Result;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const HINT_MAGIC: &[u8; 4] = b"KVSH";
+const HINT_VERSION: u32 = 2;
+const HINT_HEADER_LEN: usize = 16;
+const V1_ENTRY_HEADER_LEN: usize = 21;
+const ENTRY_HEADER_LEN: usize = 29;
+
+/// Where one record of a key is in the log.
+#[derive(Debug)]
+pub(crate) struct HintEntry {
+    pub key: String,
+    pub record_type: RecordType,
+    pub begin: u64,
+    pub end: u64,
+    pub seq: u64,
+}
+
+/// The hint file of log generation `gen`.
+pub(crate) fn hint_path(dir_path: &Path, gen: u64) -> PathBuf {
+    dir_path.join(format!("log_{}.hint", gen))
+}
+
+/// Write the hint of log `gen`, covering its first `log_len` bytes.
+///
+/// The hint is not synced: a hint lost or torn by a crash fails its checksum and the
+/// log is read instead.
+pub(crate) fn write(dir_path: &Path, gen: u64, log_len: u64, entries: &[HintEntry]) -> Result<()> {
+    let path = hint_path(dir_path, gen);
+    let tmp_path = path.with_extension("hint.tmp");
+    let mut checksum = crc32fast::Hasher::new();
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    let mut write = |bytes: &[u8]| -> io::Result<()> {
+        checksum.update(bytes);
+        writer.write_all(bytes)
+    };
+
+    write(HINT_MAGIC)?;
+    write(&HINT_VERSION.to_le_bytes())?;
+    write(&log_len.to_le_bytes())?;
+    for entry in entries {
+        write(&[entry.record_type as u8])?;
+        write(&(entry.key.len() as u32).to_le_bytes())?;
+        write(&entry.begin.to_le_bytes())?;
+        write(&entry.end.to_le_bytes())?;
+        write(&entry.seq.to_le_bytes())?;
+        write(entry.key.as_bytes())?;
+    }
+    let checksum = checksum.finalize();
+    writer.write_all(&checksum.to_le_bytes())?;
+    writer.flush()?;
+    drop(writer);
+
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+/// Read the hint of log `gen`, whose file is `log_len` bytes long. Returns how many bytes
+/// of the log the hint covers and its entries, or `None` if there is no usable hint.
+pub(crate) fn read(dir_path: &Path, gen: u64, log_len: u64) -> Result<Option<(u64, Vec<HintEntry>)>> {
+    let path = hint_path(dir_path, gen);
+    let buffer = match fs::read(&path) {
+        Ok(buffer) => buffer,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let hint = decode(&buffer).filter(|(covered, _)| *covered <= log_len);
+    if hint.is_none() {
+        log::warn!("ignoring invalid hint file {}", path.display());
+    }
+    Ok(hint)
+}
+
+/// Delete the hint of log `gen`, if it has one.
+pub(crate) fn remove(dir_path: &Path, gen: u64) -> Result<()> {
+    match fs::remove_file(hint_path(dir_path, gen)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn decode(buffer: &[u8]) -> Option<(u64, Vec<HintEntry>)> {
+    let (body, checksum) = buffer.split_at_checked(buffer.len().checked_sub(4)?)?;
+    if crc32fast::hash(body).to_le_bytes() != checksum
+        || body.len() < HINT_HEADER_LEN
+        || &body[..4] != HINT_MAGIC
+    {
+        return None;
+    }
+    let header_len = match u32_at(body, 4) {
+        1 => V1_ENTRY_HEADER_LEN,
+        HINT_VERSION => ENTRY_HEADER_LEN,
+        _ => return None,
+    };
+    let log_len = u64_at(body, 8);
+
+    let mut entries = Vec::new();
+    let mut rest = &body[HINT_HEADER_LEN..];
+    while !rest.is_empty() {
+        let header = rest.get(..header_len)?;
+        let record_type = RecordType::from_u8(header[0])?;
+        let key_len = u32_at(header, 1) as usize;
+        let key = rest.get(header_len..header_len + key_len)?;
+        entries.push(HintEntry {
+            key: String::from_utf8(key.to_vec()).ok()?,
+            record_type,
+            begin: u64_at(header, 5),
+            end: u64_at(header, 13),
+            seq: if header_len == ENTRY_HEADER_LEN { u64_at(header, 21) } else { 0 },
+        });
+        rest = &rest[header_len + key_len..];
+    }
+    Some((log_len, entries))
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+fn u64_at(buf: &[u8], at: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[at..at + 8]);
+    u64::from_le_bytes(bytes)
+}
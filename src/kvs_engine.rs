@@ -1,11 +1,21 @@
 
-use super::{Result, KvsError};
-use std::path::PathBuf;
-use std::fs;
-use std::fs::{File, OpenOptions};
-use sled;
+use super::{Result, WriteBatch};
+use std::ops::RangeBounds;
+use std::time::{Duration, SystemTime};
 
+/// Key/value pairs in key order, as returned by `KvsEngine::scan`.
+pub type ScanIter = Box<dyn Iterator<Item = Result<(String, String)>> + Send>;
 
+/// One version of a key, as returned by `KvsEngine::history`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyVersion {
+    /// Sequence number of the write that made it, see `KvsEngine::get_at`.
+    pub seq: u64,
+    /// The value that was set, `None` for a removal.
+    pub value: Option<String>,
+    /// When it was written, `None` for writes from releases that did not record it.
+    pub written_at: Option<SystemTime>,
+}
 
 /// an Engine to store <key, value>
 pub trait KvsEngine : Clone + Send + 'static {
@@ -15,8 +25,52 @@ pub trait KvsEngine : Clone + Send + 'static {
     /// try to get the value from kvsEngine with corresponding key, if it doesn't exist, then return None
     fn get(&self, key: String) -> Result<Option<String>>;
 
+    /// the value `key` had right after the write with sequence number `seq`. `None` if it
+    /// did not exist then, or if that version is no longer retained.
+    fn get_at(&self, key: String, seq: u64) -> Result<Option<String>>;
+
+    /// the retained versions of `key`, newest first, removals included.
+    fn history(&self, key: String) -> Result<Vec<KeyVersion>>;
+
     /// set the <key, value> in the kvsEngine, if key is existed, then override with the new value.
     fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// set the <key, value> like `set`, but the key reads as absent once `ttl` has passed.
+    /// A later `set` of the key clears the expiry.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()>;
+
+    /// how long until `key` expires, `None` if it never does.
+    /// Fails with `KvsError::KeyNotFound` if the key does not exist or has expired.
+    fn ttl(&self, key: String) -> Result<Option<Duration>>;
+
+    /// set `key` to `new`, or remove it if `new` is `None`, but only if its current value
+    /// is `expected`, where `None` means absent. Fails with `KvsError::Conflict` otherwise.
+    /// The check and the write are atomic with respect to other writes to the engine.
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<()>;
+
+    /// set `key` to `value` unless it already has a value, see `compare_and_swap`.
+    fn set_if_absent(&self, key: String, value: String) -> Result<()> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// remove `key` only if its value is `expected`, see `compare_and_swap`.
+    fn remove_if_equals(&self, key: String, expected: String) -> Result<()> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+
+    /// add `delta` to the integer stored at `key`, taken as 0 if the key does not exist,
+    /// and return the new value. Fails with `KvsError::NotANumber` if the value is not an
+    /// `i64`. The read and the write are atomic with respect to other writes to the engine.
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64>;
+
+    /// apply every write in `batch` atomically, in order.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// the <key, value> pairs whose key falls in `range`, in key order, at most `limit` of them.
+    fn scan<R: RangeBounds<String>>(&self, range: R, limit: usize) -> Result<ScanIter>;
+
+    /// every <key, value> pair whose key starts with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: &str) -> Result<ScanIter>;
 }
 
 /*
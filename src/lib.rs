@@ -2,196 +2,354 @@
 
 //! The `KvStore` stores <key,value>(string,string) pairs.
 //!
-//! <Key,value> pairs are stored in a `HashMap` in memory and not persisted to disk.
+//! <Key,value> pairs are stored in log files and sstables on disk, with an ordered index in memory.
 
 /// test
+mod bloom;
 mod error;
+mod file_layer;
+mod group_commit;
+mod hint;
 mod kvs_engine;
+mod manifest;
+mod merge;
+mod options;
+mod record;
+mod snapshot;
+mod sstable;
 pub mod thread_pool;
+mod transaction;
+mod write_batch;
+pub use bloom::BloomStats;
 pub use error::{Result, KvsError};
-pub use kvs_engine::{KvsEngine};
+pub use file_layer::{FileLayer, LogFile};
+pub use kvs_engine::{KeyVersion, KvsEngine, ScanIter};
+pub use options::{CompactionTrigger, KvStoreOptions, SyncPolicy, VersionRetention};
+pub use snapshot::Snapshot;
+pub use transaction::Transaction;
+pub use write_batch::WriteBatch;
 // pub use thread_pool::{ThreadPool, NaiveThreadPool, SharedQueueThreadPool, RayonThreadPool};
 
-use std::collections::HashMap;
+use record::{FileFormat, Record, RecordReader, RecordType, FILE_HEADER_LEN, FORMAT_VERSION};
+use bloom::BloomCounters;
+use group_commit::GroupCommit;
+use hint::HintEntry;
+use manifest::Manifest;
+use merge::{MergeByKey, Source};
+use snapshot::{Retained, Version};
+use sstable::SsTable;
+
+use fs2::FileExt;
+use std::collections::btree_map::Entry as IndexEntry;
+use std::collections::{BTreeMap, HashMap};
 use std::clone::Clone;
-// use std::io;
-use std::io::{Write,Read};
-use std::path::PathBuf;
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::ffi::OsString;
+use std::iter;
+use std::process;
 
 use std::fs;
 use std::fs::{File, OpenOptions};
 
-use serde::{Serialize, Deserialize};  
-
 use std::io::SeekFrom;
+use std::ops::{Bound, RangeBounds};
 use std::io::prelude::*;
-use std::sync::{Arc, Mutex};
-use std::ops::{Deref,DerefMut};
-use std::convert::AsRef;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
 // use crate::{KvsError, Result};
 
-/// a Map based on HashMap to store <key, value> in memory
+/// scan 一次从 index 里拿多少个 key
+const SCAN_CHUNK: usize = 256;
+
+/// How many times `KvStore::transaction` runs a transaction that keeps conflicting
+/// with other writes before it gives up.
+pub const MAX_TRANSACTION_ATTEMPTS: usize = 64;
+
+/// a store of <key, value> pairs on disk, with an ordered index of the keys in memory
 #[derive(Debug)]
 pub struct KvStore {
     dir_path : Arc<PathBuf>,
-    file :Arc<Mutex<File>>,
-    index_map:Arc<Mutex<HashMap<String, Index>>>,
-    offset_begin: Arc<Mutex<usize>>,
-    log_file_path : Arc<PathBuf>,
-    item_count :Arc<Mutex<u64>>, // 用来统计有多少条命令了，是不是要切了
-    sstable_path_vec:Arc<Mutex<Vec<String>>>, // 这个存放的是压缩后的文件，按照sstable_x.txt命名，从_1开始
+    file :Arc<Mutex<Option<LogWriter>>>, // 当前正在写的 log，按照 log_x.txt 命名；只读打开的时候没有
+    log_gen: Arc<Mutex<u64>>, // 当前正在写的 log 的编号
+    sealed_logs: Arc<Mutex<Vec<u64>>>, // 已经写满、等着被压缩的 log
+    index_map:Arc<RwLock<BTreeMap<String, Index>>>, // 所有 log 里的 key，按顺序排好，scan 的时候要用
+    log_versions: Arc<RwLock<HashMap<String, Vec<Index>>>>, // 要留多个版本的时候，log 里被替换掉的记录，按 seq 从旧到新；在 index 的锁后面拿
+    log_readers:Arc<RwLock<HashMap<u64, Arc<File>>>>, // 每个 log 一个只读的句柄，大家一起用，读的时候不碰写锁
+    offset_begin: Arc<Mutex<u64>>,
+    log_stats :Arc<Mutex<LogStats>>, // 用来统计 log 里有多少条命令了，是不是要切了
+    sstables:Arc<RwLock<Vec<Arc<SsTable>>>>, // 这个存放的是压缩后的文件，按照 generation 从小到大排，越后面越新
+    manifest: Arc<Mutex<Manifest>>, // 和磁盘上的 MANIFEST 一致；改的时候先写盘再改这里
+    compaction: Arc<Compaction>,
+    options: Arc<KvStoreOptions>,
+    flusher: Arc<Flusher>,
+    commit: Arc<GroupCommit<Vec<Record>>>, // 并发的写入攒成一批，一次写入一次落盘；一个 Vec 是一次原子的写入
+    bloom_counters: Arc<BloomCounters>,
+    seq: Arc<AtomicU64>, // 最后一次写入的 seq，拿着 log 的锁改
+    retained: Arc<Mutex<Retained>>, // 打开着的快照和为它们留下来的记录
+    // 目录锁放在最后：字段按顺序 drop，要等后台压缩和落盘线程都停了才放锁
+    lock: Arc<Option<File>>,
+}
+
+/// 正在写的 log 文件，记着上次落盘以后又写了几条
+#[derive(Debug)]
+struct LogWriter {
+    file: Box<dyn LogFile>,
+    unsynced: u64,
+    broken: bool, // 写失败以后没能把写了一半的截掉，文件末尾不知道是什么了，不能再往后写
+}
+
+impl LogWriter {
+    /// 把还没落盘的写入落盘
+    fn sync(&mut self) -> Result<()> {
+        if self.unsynced > 0 {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
-struct Command {
-    action :String,
-    key :String,
-    value :String,
+/// 还没有压缩进 sstable 的那些 log 的统计
+#[derive(Debug, Default)]
+struct LogStats {
+    records: u64,
+    bytes: u64,
+    stale_bytes: u64, // 被后面的写入覆盖或者删掉的记录占的字节
 }
 
 
+/// 一条记录在文件里的位置；删除的记录也要留着，挡住 sstable 里更早的值
 #[derive(Debug)]
 struct Index {
+    gen :u64,
+    record_type :RecordType,
+    seq :u64,
     offset_begin :u64,
     offset_end  :u64,
 }
 
+impl Index {
+    fn new(gen: u64, record_type: RecordType, seq: u64, offset_begin: u64, offset_end: u64) -> Index {
+        Index{
+            gen,
+            record_type,
+            seq,
+            offset_begin,
+            offset_end,
+        }
+    }
+}
+
+/// 后台压缩线程。最后一个 KvStore 被 drop 的时候会等它做完，免得和重新打开的 store 抢文件
+#[derive(Debug, Default)]
+struct Compaction {
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Drop for Compaction {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// SyncPolicy::Interval 用的后台落盘线程。最后一个 KvStore 被 drop 的时候叫它停下，
+/// 停之前再落一次盘
+#[derive(Debug, Default)]
+struct Flusher {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    fn start(file: Arc<Mutex<Option<LogWriter>>>, interval: Duration) -> Result<Flusher> {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let flag = stop.clone();
+        let handle = thread::Builder::new()
+            .name(String::from("kvs-flusher"))
+            .spawn(move || {
+                let (stopped, wakeup) = &*flag;
+                let mut stopped = stopped.lock().unwrap();
+                while !*stopped {
+                    stopped = wakeup.wait_timeout(stopped, interval).unwrap().0;
+                    if let Some(writer) = file.lock().unwrap().as_mut() {
+                        if let Err(e) = writer.sync() {
+                            log::error!("failed to sync log: {}", e);
+                        }
+                    }
+                }
+            })?;
+        Ok(Flusher{stop, handle: Some(handle)})
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            *self.stop.0.lock().unwrap() = true;
+            self.stop.1.notify_one();
+            let _ = handle.join();
+        }
+    }
+}
+
 
 impl KvsEngine for KvStore {
     /// set the <key, value> in the KvStore, if key is existed, then override with the new value.
     fn set(&self, key: String, value: String) -> Result<()>{
-        let command = serde_json::to_string(&Command{
-            action: String::from("set"),
-            key:key,
-            value:value,
-        })?;      
+        self.append(vec![Record::set(key, value)])
+    }
+
+    /// set the <key, value> with an expiry time `ttl` from now, which is stored in the log record.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let expires_at = record::now_millis().saturating_add(ttl);
+        self.append(vec![Record::set_expiring(key, value, expires_at)])
+    }
 
+    /// how long until `key` expires, `None` if it has no expiry.
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        let now = record::now_millis();
+        match self.find(&key, now)? {
+            Some(record) => Ok(record.expires_at.map(|expires_at| Duration::from_millis(expires_at - now))),
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    /// set or remove `key` only if its current value is `expected`. The check and the write
+    /// happen under the log's lock, so no other write to this store can come in between.
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<()> {
+        if self.options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
         let mut guard = self.file.lock().unwrap();
-        guard.write_all(command.as_bytes())?;
-        self.load_index(&mut guard)?;
-        drop(guard);
+        // 所有写入更新 index 的时候都拿着这把锁，这里读到的就是最新的值
+        if self.get(key.clone())? != expected {
+            return Err(KvsError::Conflict);
+        }
+        let record = match new {
+            Some(value) => Record::set(key, value),
+            None if expected.is_some() => Record::remove(key),
+            None => return Ok(()),
+        };
+        self.write_locked(&mut guard, vec![vec![record]])
+    }
 
-        Ok(())
+    /// add `delta` to the integer at `key`; like `compare_and_swap` the read and the write
+    /// happen under the log's lock.
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        if self.options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let mut guard = self.file.lock().unwrap();
+        let current = match self.get(key.clone())? {
+            None => 0,
+            Some(value) => value.parse::<i64>().map_err(|_| KvsError::NotANumber(value))?,
+        };
+        let value = current
+            .checked_add(delta)
+            .ok_or_else(|| KvsError::InvalidArgument(format!("{} + {} overflows", current, delta)))?;
+        self.write_locked(&mut guard, vec![vec![Record::set(key, value.to_string())]])?;
+        Ok(value)
+    }
+
+    /// apply every write in `batch` atomically: they go into the log as one record.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.append(batch.records)
     }
 
     /// try to get the value from KvStore with corresponding key, if it doesn't exist, then return None
     fn get(&self, key: String) -> Result<Option<String>> {
-        if self.index_map.lock().unwrap().contains_key(&key) {
-            let index = &self.index_map.lock().unwrap()[&key];
-            let length = index.offset_end - index.offset_begin;
-
-            use std::io::BufReader;
-
-            let f = &*self.file.lock().unwrap();
-            let mut reader = BufReader::new(f); 
+        Ok(self.find(&key, record::now_millis())?.map(|record| record.value))
+    }
 
-            reader.seek(SeekFrom::Start(index.offset_begin))?;
-            let cmd_reader = reader.take(length);
+    /// the value of `key` as of the write `seq`, looked up among the retained versions.
+    fn get_at(&self, key: String, seq: u64) -> Result<Option<String>> {
+        let now = record::now_millis();
+        let version = self.versions(&key)?.into_iter().find(|record| record.seq <= seq);
+        Ok(version.filter(|record| record.is_live(now)).map(|record| record.value))
+    }
 
-            let command: Command = serde_json::from_reader(cmd_reader)?;
-            
-            Ok(Some(command.value))
-        } else {
-            //开始倒序寻找
-            for file_name in self.sstable_path_vec.lock().unwrap().deref() {
-                let mut path = PathBuf::new();
-                path.push(self.dir_path.as_ref());
-                path.push(file_name);
-
-                let mut file = OpenOptions::new()
-                        .read(true)
-                        .write(true)
-                        .append(true)
-                        .create(true)
-                        .open(path)?;
-
-                //先把他们直接读进来处理
-                let mut buffer = String::new();
-                file.read_to_string(&mut buffer)?;
-                
-                let mut indices = serde_json::Deserializer::from_str(&buffer).into_iter::<Command>();
-
-                while let Some(command) = indices.next() {
-                    let command = command?;
-                    if command.key == key {
-                        if command.action == "set" {
-                            return Ok(Some(command.value))
-                        } else if command.action == "rm" {
-                            return Ok(None)
-                        }
-                    }
-                    
-                }
-            }
-            Ok(None)
-        }
+    /// the retained versions of `key`, newest first.
+    fn history(&self, key: String) -> Result<Vec<KeyVersion>> {
+        let versions = self.versions(&key)?;
+        Ok(versions
+            .into_iter()
+            .map(|record| KeyVersion {
+                seq: record.seq,
+                value: (record.record_type == RecordType::Set).then_some(record.value),
+                written_at: (record.written_at > 0)
+                    .then(|| SystemTime::UNIX_EPOCH + Duration::from_millis(record.written_at)),
+            })
+            .collect())
     }
 
     /// try to remove the <key,value> from KvStore with the given Key, if doesn't exist this key, then do nothing.
     fn remove(&self, key: String) -> Result<()> {
-        if self.index_map.lock().unwrap().contains_key(&key) {
-            let command = serde_json::to_string(&Command{
-                action: String::from("rm"),
-                key:key,
-                value:String::from(""),
-            })?;      
-    
-            let mut guard = self.file.lock().unwrap();
-            guard.write_all(command.as_bytes())?;
-            self.load_index(&mut guard)?;
-            drop(guard);
+        // 过期了的 key 也算不存在，所以要把记录读出来看
+        if self.find(&key, record::now_millis())?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
 
-            Ok(())
-        } else {
-            for file_name in self.sstable_path_vec.lock().unwrap().deref() {
-                let mut path = PathBuf::new();
-                path.push(self.dir_path.as_ref());
-                path.push(file_name);
-
-                let mut file = OpenOptions::new()
-                        .read(true)
-                        .write(true)
-                        .append(true)
-                        .create(true)
-                        .open(path)?;
-
-                //先把他们直接读进来处理
-                let mut buffer = String::new();
-                file.read_to_string(&mut buffer)?;
-                
-                let mut indices = serde_json::Deserializer::from_str(&buffer).into_iter::<Command>();
-
-                while let Some(command) = indices.next() {
-                    let command = command?;
-                    if command.key == key {
-                        if command.action == "set" {
-                            let command = serde_json::to_string(&Command{
-                                action: String::from("rm"),
-                                key:key,
-                                value:String::from(""),
-                            })?;      
-                    
-                            let mut guard = self.file.lock().unwrap();
-                            guard.write_all(command.as_bytes())?;
-                            self.load_index(&mut guard)?;
-                            drop(guard);
-                            
-                            return Ok(())
-                        } else if command.action == "rm" {
-                            return Err(KvsError::KeyNotFound)
-                        }
-                    }
-                    
-                }
-            }
-            Err(KvsError::KeyNotFound)
+        self.append(vec![Record::remove(key)])
+    }
+
+    /// the <key, value> pairs whose key falls in `range`, in key order, at most `limit` of them.
+    /// Keys are read a chunk at a time as the scan goes, each chunk from a consistent view of the store.
+    fn scan<R: RangeBounds<String>>(&self, range: R, limit: usize) -> Result<ScanIter> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Ok(Box::new(self.scan_range(range).take(limit)))
+    }
+
+    /// every <key, value> pair whose key starts with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: &str) -> Result<ScanIter> {
+        let range = (Bound::Included(prefix.to_owned()), prefix_end(prefix));
+        Ok(Box::new(self.scan_range(range)))
+    }
+}
+
+/// 以 prefix 开头的 key 的上界：最后一个字符换成下一个字符，比所有以 prefix 开头的都大。
+/// 最后一个字符已经是最大的就去掉再往前进，全都是的话没有上界
+fn prefix_end(prefix: &str) -> Bound<String> {
+    let mut end = prefix.to_owned();
+    while let Some(last) = end.pop() {
+        // 跳过代理区，那里的值不是 char
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            end.push(next);
+            return Bound::Excluded(end);
         }
     }
+    Bound::Unbounded
+}
+
+/// scan 找到的一条记录：log 里的只记着位置，值等到迭代的时候再读
+enum Entry {
+    Log { index: Index, file: Arc<File> },
+    Table(Record),
+}
+
+/// BTreeMap::range 遇到反过来的范围会 panic，先挡掉
+fn range_is_empty(range: &(Bound<String>, Bound<String>)) -> bool {
+    match range {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
 }
 
 impl Clone for Index {
     fn clone(&self) -> Self {
         Index{
+            gen:self.gen,
+            record_type:self.record_type,
+            seq:self.seq,
             offset_begin:self.offset_begin,
             offset_end: self.offset_end
         }
@@ -204,206 +362,1162 @@ impl Clone for KvStore {
         KvStore{
             dir_path:self.dir_path.clone(),
             file:self.file.clone(),
+            log_gen:self.log_gen.clone(),
+            sealed_logs:self.sealed_logs.clone(),
             index_map: self.index_map.clone(),
+            log_versions: self.log_versions.clone(),
+            log_readers: self.log_readers.clone(),
             offset_begin: self.offset_begin.clone(),
-            log_file_path : self.log_file_path.clone(),
-            item_count: self.item_count.clone(),
-            sstable_path_vec : self.sstable_path_vec.clone(),
+            log_stats: self.log_stats.clone(),
+            sstables : self.sstables.clone(),
+            manifest : self.manifest.clone(),
+            compaction : self.compaction.clone(),
+            options : self.options.clone(),
+            flusher : self.flusher.clone(),
+            commit : self.commit.clone(),
+            bloom_counters : self.bloom_counters.clone(),
+            seq : self.seq.clone(),
+            retained : self.retained.clone(),
+            lock : self.lock.clone(),
         }
     }
 }
 impl KvStore {
     /// Open the KvStore at a given path. Return the KvStore.
+    ///
+    /// Data files written in an older format are upgraded first, and a torn record
+    /// at the end of the log is truncated.
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        let mut path = path.into();
-        let dir_path = path.clone();
-        fs::create_dir_all(&path)?;
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
 
-        path.push("log.txt"); //这个文件是固定的
-        let index_map:HashMap<String, Index> = HashMap::new();
-        let offset_begin = 0;
-        
-        let mut sstable_path_vec : Vec<String> = Vec::new();
-        for entry in fs::read_dir(&dir_path)? {
-            let file_name = entry?.path().display().to_string();
-            let vec: Vec<&str> = file_name.split("/").collect();
-            if vec[vec.len() - 1].starts_with("sstable") {
-                sstable_path_vec.push(vec[vec.len() - 1].to_string());
+    /// Open the KvStore at a given path read-only, see `KvStoreOptions::read_only`.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self> {
+        KvStore::open_with(path, KvStoreOptions::new().read_only(true))
+    }
+
+    /// Open the KvStore at a given path with the given options.
+    ///
+    /// A writable store locks the directory until the store and all its clones are
+    /// dropped; opening it for writing again meanwhile, from this process or another,
+    /// fails with `KvsError::Locked`.
+    ///
+    /// A read-only store never touches the directory: it does not create it, lock it,
+    /// upgrade old files, truncate torn records or compact. It can be opened next to a
+    /// writer and sees the data as of the moment it was opened; if the writer replaces
+    /// files while it opens, it opens again from the new file list.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<Self> {
+        let dir_path = path.into();
+        if !options.read_only {
+            return KvStore::open_once(dir_path, options);
+        }
+        /*
+        只读打开不拿目录锁，写的那个进程随时会压缩、换 log，删掉 manifest 里列过的文件；
+        它总是先换 manifest 再删文件，所以打开到一半文件没了的话 manifest 一定变了，
+        按新的 manifest 重新打开就是了。manifest 没变的话是真的少了文件
+        */
+        loop {
+            let before = Manifest::load(&dir_path)?;
+            match KvStore::open_once(dir_path.clone(), options.clone()) {
+                Err(KvsError::MissingFile(_)) if Manifest::load(&dir_path)? != before => continue,
+                Err(KvsError::IoError(e))
+                    if e.kind() == io::ErrorKind::NotFound && Manifest::load(&dir_path)? != before =>
+                {
+                    continue
+                }
+                result => return result,
             }
         }
+    }
 
-        sstable_path_vec.sort();
+    /// 照着目录里现在的 manifest 打开一次
+    fn open_once(dir_path: PathBuf, options: KvStoreOptions) -> Result<Self> {
+        let read_only = options.read_only;
+        let lock = if read_only {
+            None
+        } else {
+            fs::create_dir_all(&dir_path)?;
+            Some(lock_dir(&dir_path)?)
+        };
+
+        // 有 manifest 就只认它里面的文件，别的都是中途挂掉留下来的；
+        // 没有的话是老版本的目录或者新目录，看目录里有什么
+        let loaded = Manifest::load(&dir_path)?;
+        let mut manifest = match loaded.clone() {
+            Some(manifest) => {
+                if !read_only {
+                    manifest.remove_stray_files(&dir_path)?;
+                }
+                manifest
+            }
+            None => {
+                if !read_only {
+                    manifest::remove_temp_files(&dir_path)?;
+                }
+                discover_files(&dir_path, read_only)?
+            }
+        };
+        for path in manifest
+            .logs
+            .iter()
+            .map(|gen| log_path(&dir_path, *gen))
+            .chain(manifest.sstables.iter().map(|gen| dir_path.join(sstable::file_name(*gen))))
+        {
+            if !path.exists() {
+                return Err(KvsError::MissingFile(path.display().to_string()));
+            }
+        }
+
+        let mut sstables = Vec::new();
+        for gen in &manifest.sstables {
+            sstables.push(Arc::new(SsTable::open(&dir_path, *gen, &options)?));
+        }
+
+        // 最新的那个 log 接着写，更早的是上次没来得及压缩的
+        let mut sealed_logs = manifest.logs.clone();
+        let log_gen = sealed_logs.pop().unwrap_or(0);
 
         //直接创建一个file
-        let file = OpenOptions::new()
-                        .read(true)
-                        .write(true)
-                        .append(true)
-                        .create(true)
-                        .open(&path)?;
-        
-
-        let mut kv_store = KvStore{
+        let file = if read_only {
+            None
+        } else {
+            Some(open_log_writer(&dir_path, log_gen, &options)?)
+        };
+        if !read_only && loaded.is_none() {
+            manifest.logs = sealed_logs.iter().copied().chain([log_gen]).collect();
+            manifest.store(&dir_path)?;
+        }
+        let file = Arc::new(Mutex::new(file));
+        let flusher = match options.sync {
+            SyncPolicy::Interval(interval) if !read_only => Flusher::start(file.clone(), interval)?,
+            _ => Flusher::default(),
+        };
+
+        let kv_store = KvStore{
             dir_path:Arc::new(dir_path),
-            file: Arc::new(Mutex::new(file)),
-            index_map: Arc::new(Mutex::new(index_map)),
-            offset_begin: Arc::new(Mutex::new(offset_begin)),
-            log_file_path : Arc::new(path),
-            item_count : Arc::new(Mutex::new(0)),
-            sstable_path_vec : Arc::new(Mutex::new(sstable_path_vec)),
+            file,
+            log_gen: Arc::new(Mutex::new(log_gen)),
+            sealed_logs: Arc::new(Mutex::new(sealed_logs.clone())),
+            index_map: Arc::new(RwLock::new(BTreeMap::new())),
+            log_versions: Arc::new(RwLock::new(HashMap::new())),
+            log_readers: Arc::new(RwLock::new(HashMap::new())),
+            offset_begin: Arc::new(Mutex::new(FILE_HEADER_LEN)),
+            log_stats : Arc::new(Mutex::new(LogStats::default())),
+            sstables : Arc::new(RwLock::new(sstables)),
+            manifest : Arc::new(Mutex::new(manifest)),
+            compaction : Arc::new(Compaction::default()),
+            options : Arc::new(options),
+            flusher : Arc::new(flusher),
+            commit : Arc::new(GroupCommit::default()),
+            bloom_counters : Arc::new(BloomCounters::default()),
+            seq : Arc::new(AtomicU64::new(0)),
+            retained : Arc::new(Mutex::new(Retained::default())),
+            lock : Arc::new(lock),
         };
 
-        let mut guard = kv_store.file.lock().unwrap();
-        kv_store.load_index(&mut guard)?;
-        drop(guard);
+        for gen in sealed_logs {
+            kv_store.load_index(gen)?;
+        }
+        // 只读打开不会创建 log，目录里可能一个都没有
+        if !read_only || log_path(&kv_store.dir_path, log_gen).exists() {
+            *kv_store.offset_begin.lock().unwrap() = kv_store.load_index(log_gen)?;
+        }
+        // 每个 key 最新的那条 seq 最大；sstable 里的由 manifest 记着
+        let last_seq = kv_store.index_map.read().unwrap().values().map(|index| index.seq).max().unwrap_or(0);
+        let last_seq = last_seq.max(kv_store.manifest.lock().unwrap().last_seq);
+        kv_store.seq.store(last_seq, Ordering::SeqCst);
+
+        if kv_store.should_compact() {
+            let mut guard = kv_store.file.lock().unwrap();
+            kv_store.start_compaction(&mut guard)?;
+        }
 
         Ok(kv_store)
 
     }
 
-    
-    fn load_index(&self, guard:&mut std::sync::MutexGuard<File>) -> Result<()> {
-        guard.seek(SeekFrom::Start(*self.offset_begin.lock().unwrap().deref() as u64))?;
-        
-        let mut buffer = String::new();
-
-        // 读取整个文件
-        guard.read_to_string(&mut buffer)?;
-
-        //反序列化
-        let mut indices = serde_json::Deserializer::from_str(&buffer).into_iter::<Command>();
-
-        let mut offset_begin = 0;
-
-        while let Some(command) = indices.next() {
-            let offset_end = indices.byte_offset();
-            let command = command?;
-            if command.action == "set" {
-                let command_key = command.key;
-
-                let new_offset_begin = (offset_begin + self.offset_begin.lock().unwrap().deref()) as u64;
-                let new_offset_end = (offset_end + self.offset_begin.lock().unwrap().deref()) as u64;
-                self.index_map.lock().unwrap().insert(command_key, 
-                    Index{
-                        offset_begin: new_offset_begin,
-                        offset_end:new_offset_end,
-                    });
-            } else if command.action == "rm" {
-                let command_key = command.key;
-                
-                if self.index_map.lock().unwrap().contains_key(&command_key) {
-                    self.index_map.lock().unwrap().remove_entry(&command_key);
+    /// A read-only view of the store as it is now, which later writes do not change.
+    /// Use it to read several keys as of one moment while writes go on.
+    pub fn snapshot(&self) -> Snapshot {
+        // 拿着 log 的锁，seq 以前的写入都已经进了 index；
+        // 拿着 sstables 的锁登记，压缩要么看得到这个快照，要么已经把 sstable 换好了
+        let _guard = self.file.lock().unwrap();
+        let tables = self.sstables.read().unwrap();
+        let seq = self.seq.load(Ordering::SeqCst);
+        self.retained.lock().unwrap().register(seq);
+        Snapshot::new(self.clone(), seq, record::now_millis(), tables.clone())
+    }
+
+    /// Run `f` as an optimistic transaction and return what it returns.
+    ///
+    /// `f` reads and writes through the `Transaction` it is given. When it returns `Ok`
+    /// its writes are committed as one atomic log record, provided no key it read has
+    /// been written to since the attempt started. Otherwise `f` is run again from
+    /// scratch, up to `MAX_TRANSACTION_ATTEMPTS` times before giving up with
+    /// `KvsError::Conflict`; it should not have side effects outside the transaction.
+    /// An error returned by `f` aborts the transaction without writing anything.
+    ///
+    /// ```no_run
+    /// # use kvs::KvStore;
+    /// # let store = KvStore::open("data")?;
+    /// store.transaction(|txn| {
+    ///     let from: i64 = txn.get("alice".to_owned())?.map_or(0, |v| v.parse().unwrap());
+    ///     let to: i64 = txn.get("bob".to_owned())?.map_or(0, |v| v.parse().unwrap());
+    ///     txn.set("alice".to_owned(), (from - 10).to_string());
+    ///     txn.set("bob".to_owned(), (to + 10).to_string());
+    ///     Ok(())
+    /// })?;
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    pub fn transaction<T, F>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Transaction) -> Result<T>,
+    {
+        for _ in 0..MAX_TRANSACTION_ATTEMPTS {
+            let mut txn = Transaction::new(self.snapshot());
+            let result = f(&mut txn)?;
+            match self.commit(txn) {
+                Err(KvsError::Conflict) => continue,
+                Err(e) => return Err(e),
+                Ok(()) => return Ok(result),
+            }
+        }
+        Err(KvsError::Conflict)
+    }
+
+    /*
+    提交一个事务：拿着 log 的锁核对读过的 key，从事务开始以后都没人写过、
+    读到的值也没有过期，才把写入作为一条记录写进去。
+    和条件写入一样，核对和写入之间不会插进别的写入
+    */
+    fn commit(&self, txn: Transaction) -> Result<()> {
+        if self.options.read_only && !txn.writes.is_empty() {
+            return Err(KvsError::ReadOnly);
+        }
+        let mut guard = self.file.lock().unwrap();
+        let now = record::now_millis();
+        for (key, read) in &txn.reads {
+            let newest = self.newest(key)?;
+            if newest.as_ref().is_some_and(|record| record.seq > txn.snapshot.seq()) {
+                return Err(KvsError::Conflict);
+            }
+            if newest.filter(|record| record.is_live(now)).map(|record| record.value) != *read {
+                return Err(KvsError::Conflict);
+            }
+        }
+        if txn.writes.is_empty() {
+            return Ok(());
+        }
+
+        let records = txn
+            .writes
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => Record::set(key, value),
+                None => Record::remove(key),
+            })
+            .collect();
+        self.write_locked(&mut guard, vec![records])
+    }
+
+    /// Write a consistent copy of the store into `dest_dir`, which can then be opened
+    /// like any other store, while reads and writes go on.
+    ///
+    /// The copy holds every write that finished before the call. Sstables never change
+    /// once written, so they are hard-linked into `dest_dir` when it is on the same
+    /// filesystem and copied otherwise; the logs are copied. The copy is built next to
+    /// `dest_dir` and moved into place when complete, so `dest_dir` must not exist or
+    /// be empty, and it never holds a partial copy.
+    pub fn checkpoint(&self, dest_dir: impl AsRef<Path>) -> Result<()> {
+        let dest_dir = dest_dir.as_ref();
+        let not_empty = || {
+            KvsError::InvalidArgument(format!("checkpoint directory {} is not empty", dest_dir.display()))
+        };
+        if fs::read_dir(dest_dir).is_ok_and(|mut entries| entries.next().is_some()) {
+            return Err(not_empty());
+        }
+        let name = dest_dir.file_name().ok_or_else(|| {
+            KvsError::InvalidArgument(format!("invalid checkpoint directory {}", dest_dir.display()))
+        })?;
+        let parent = match dest_dir.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(parent)?;
+
+        /*
+        先在旁边一个只有自己用的临时目录里做好，再整个 rename 过去：
+        rename 到不存在或者空的目录上才会成功，两个备份抢同一个目录只有一个能成。
+        失败的话只删自己的临时目录，别人做好的备份不会动
+        */
+        static CHECKPOINTS: AtomicUsize = AtomicUsize::new(0);
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(format!(".checkpoint-{}-{}", process::id(), CHECKPOINTS.fetch_add(1, Ordering::SeqCst)));
+        let tmp_dir = parent.join(tmp_name);
+        fs::create_dir(&tmp_dir)?;
+        let result = self.copy_into(&tmp_dir).and_then(|()| match fs::rename(&tmp_dir, dest_dir) {
+            Ok(()) => sync_dir(parent),
+            Err(e) if matches!(e.kind(), io::ErrorKind::DirectoryNotEmpty | io::ErrorKind::AlreadyExists) => {
+                Err(not_empty())
+            }
+            Err(e) => Err(e.into()),
+        });
+        if result.is_err() {
+            if let Err(e) = fs::remove_dir_all(&tmp_dir) {
+                log::error!("failed to clean up checkpoint directory {}: {}", tmp_dir.display(), e);
+            }
+        }
+        result
+    }
+
+    /*
+    拿着 log 的锁，写入停在一条记录的末尾，记下当前的 log 写到哪了；
+    一直拿着 manifest 的锁，压缩换不了 manifest，也就删不了 manifest 里的文件。
+    锁里只做快的事：sstable 硬链接过去，链接不了的和 log、hint 一样先打开拿着句柄，
+    放了锁以后再慢慢复制，压缩把它们删了也不要紧
+    */
+    fn copy_into(&self, dest_dir: &Path) -> Result<()> {
+        let (manifest, active_len, tables, handles) = {
+            let _guard = self.file.lock().unwrap();
+            let active_gen = *self.log_gen.lock().unwrap();
+            let active_len = *self.offset_begin.lock().unwrap();
+            let manifest = self.manifest.lock().unwrap();
+            let mut tables = Vec::new();
+            for gen in &manifest.sstables {
+                let name = sstable::file_name(*gen);
+                let path = self.dir_path.join(&name);
+                if fs::hard_link(&path, dest_dir.join(&name)).is_err() {
+                    tables.push((name, File::open(&path)?));
                 }
             }
+            let mut handles = Vec::new();
+            for gen in &manifest.logs {
+                let log = File::open(log_path(&self.dir_path, *gen))?;
+                let hint = match File::open(hint::hint_path(&self.dir_path, *gen)) {
+                    Ok(hint) if *gen != active_gen => Some(hint),
+                    _ => None,
+                };
+                handles.push((*gen, *gen == active_gen, log, hint));
+            }
+            (manifest.clone(), active_len, tables, handles)
+        };
 
-            *self.item_count.lock().unwrap() += 1;
-            offset_begin = offset_end;
+        for (name, table) in tables {
+            copy_file(&table, &dest_dir.join(name), u64::MAX)?;
+        }
+        for (gen, active, log, hint) in handles {
+            // 正在写的 log 只复制到记下来的位置，后面的写入不算
+            let len = if active { active_len } else { u64::MAX };
+            copy_file(&log, &log_path(dest_dir, gen), len)?;
+            if let Some(hint) = hint {
+                copy_file(&hint, &hint::hint_path(dest_dir, gen), u64::MAX)?;
+            }
         }
+        // manifest 最后写，有它的目录才是一个完整的 store
+        manifest.store(dest_dir)
+    }
 
-        *self.offset_begin.lock().unwrap() += offset_begin;
+    /// How many sstable reads the Bloom filters have saved since the store was opened.
+    /// Shared by all clones of the store.
+    pub fn bloom_stats(&self) -> BloomStats {
+        self.bloom_counters.snapshot()
+    }
 
-        // 设定条目超过 2000 就触发压缩
-        if *self.item_count.lock().unwrap() > 2000 {
-            self.compact(guard)?
+    /// 追加一次写入，和同时在写的其他线程的写入一起提交，等这一批按照 sync 的设定落盘了才返回。
+    /// 多条记录的写入要么全写进去要么全没有
+    fn append(&self, records: Vec<Record>) -> Result<()> {
+        if self.options.read_only {
+            return Err(KvsError::ReadOnly);
         }
-        Ok(())
+        self.commit.commit(records, |batch| self.append_batch(batch))
     }
 
-    ///先对文件中切分后剩下的内容重新写入文件，并且更新index情况
-    fn restore_rest_file(&self, offset: u64, guard:&mut std::sync::MutexGuard<File>) -> Result<()> {
-        guard.seek(SeekFrom::Start(offset))?;
-        let mut buffer = String::new();
-        guard.read_to_string(&mut buffer)?;
-        fs::remove_file(self.log_file_path.deref())?;
+    /// 一批写入一次写进 log，最多落一次盘，然后直接用写入的位置更新 index，不再回头重读 log
+    fn append_batch(&self, batch: Vec<Vec<Record>>) -> Result<()> {
+        let mut guard = self.file.lock().unwrap();
+        self.write_locked(&mut guard, batch)
+    }
 
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(self.log_file_path.deref())?;
-        
-        **(guard) = file;
+    /// 拿着 log 的锁写入：条件写入先在锁里比较，再接着用这个锁写
+    fn write_locked(&self, guard: &mut MutexGuard<Option<LogWriter>>, batch: Vec<Vec<Record>>) -> Result<()> {
+        let writes = batch.len() as u64;
+        let mut buffer = Vec::new();
+        let mut spans = Vec::new();
+        // 一次写入里的记录用同一个 seq，快照要么全看得到要么全看不到；
+        // 快照要拿这把锁才能读 seq，所以先改掉也不会被看到写了一半的
+        let last_seq = self.seq.load(Ordering::SeqCst);
+        let mut seq = last_seq;
+        let now = record::now_millis();
+        for mut records in batch {
+            seq += 1;
+            for record in &mut records {
+                record.seq = seq;
+                record.written_at = now;
+            }
+            let base = buffer.len() as u64;
+            if records.len() == 1 {
+                buffer.extend_from_slice(&records[0].encode());
+                spans.push((records.pop().unwrap(), base, buffer.len() as u64));
+            } else {
+                let (frame, frame_spans) = Record::encode_batch(&records);
+                buffer.extend_from_slice(&frame);
+                for (record, (begin, end)) in records.into_iter().zip(frame_spans) {
+                    spans.push((record, base + begin, base + end));
+                }
+            }
+        }
+        self.seq.store(seq, Ordering::SeqCst);
+
+        let gen = *self.log_gen.lock().unwrap();
+        let start = *self.offset_begin.lock().unwrap();
+        let writer = guard.as_mut().ok_or(KvsError::ReadOnly)?;
+        if writer.broken {
+            return Err(KvsError::IoError(io::Error::other("the log is unusable after a failed write")));
+        }
+        let unsynced = writer.unsynced;
+        let result = (|| -> Result<()> {
+            writer.file.write_all(&buffer)?;
+            writer.file.flush()?;
+            writer.unsynced += writes;
+            match self.options.sync {
+                SyncPolicy::Always => writer.sync(),
+                SyncPolicy::EveryN(n) if writer.unsynced >= n => writer.sync(),
+                _ => Ok(()),
+            }
+        })();
+        if let Err(e) = result {
+            /*
+            调用的人拿到的是失败，可能已经写进去的部分要截掉：
+            留着的话后面的写入都记在错的位置上，重新打开以后这次失败的写入又冒出来了。
+            截不掉的话这个 log 就不能再写了
+            */
+            self.seq.store(last_seq, Ordering::SeqCst);
+            writer.unsynced = unsynced;
+            let truncated = OpenOptions::new()
+                .write(true)
+                .open(log_path(&self.dir_path, gen))
+                .and_then(|file| file.set_len(start));
+            if let Err(truncate_error) = truncated {
+                log::error!("failed to discard a failed write to log {}: {}", gen, truncate_error);
+                writer.broken = true;
+            }
+            return Err(e);
+        }
 
-        *self.offset_begin.lock().unwrap() = 0;
-        *self.item_count.lock().unwrap() = 0;
+        for (record, begin, end) in spans {
+            let index = Index::new(gen, record.record_type, record.seq, start + begin, start + end);
+            self.update_index(record.key, index);
+        }
+        let end = start + buffer.len() as u64;
+        *self.offset_begin.lock().unwrap() = end;
 
-        if buffer.len() != 0 {
-            guard.write_all(buffer.as_bytes())?;
-            self.load_index(guard)?;
+        /*
+        压缩的时候本来就会换新的 log，不用再按大小切。
+        到这里记录已经写进去、index 也更新了，换 log 失败不能算这次写入失败，
+        不然调用的人会以为没写成功；下次写入的时候还会再试
+        */
+        let rolled = if self.should_compact() {
+            self.start_compaction(guard)
+        } else if end >= self.options.max_log_size {
+            self.roll_log(guard)
+        } else {
+            Ok(())
+        };
+        if let Err(e) = rolled {
+            log::error!("failed to seal log {}: {}", gen, e);
         }
         Ok(())
     }
 
+    /// 给封存的第 gen 个 log 写 hint：这时候它还是最新的 log，index 里所有指向它的 key 都要记下来
+    /// 留着的旧版本也要写进去，排在最新的那条前面，打开的时候按顺序放回 index 就对了
+    fn write_hint(&self, gen: u64, log_len: u64) -> Result<()> {
+        let index_map = self.index_map.read().unwrap();
+        let log_versions = self.log_versions.read().unwrap();
+        let mut entries = Vec::new();
+        for (key, index) in index_map.iter().filter(|(_, index)| index.gen == gen) {
+            let older = log_versions.get(key).into_iter().flatten().filter(|index| index.gen == gen);
+            for index in older.chain([index]) {
+                entries.push(HintEntry {
+                    key: key.clone(),
+                    record_type: index.record_type,
+                    begin: index.offset_begin,
+                    end: index.offset_end,
+                    seq: index.seq,
+                });
+            }
+        }
+        drop(log_versions);
+        drop(index_map);
+        hint::write(&self.dir_path, gen, log_len, &entries)
+    }
+
+    /// 按照配置的触发条件，看现在要不要压缩
+    fn should_compact(&self) -> bool {
+        if self.options.read_only {
+            return false;
+        }
+        let stats = self.log_stats.lock().unwrap();
+        match self.options.compaction {
+            CompactionTrigger::RecordCount(count) => stats.records > count,
+            CompactionTrigger::StaleRatio { ratio, min_bytes } => {
+                stats.stale_bytes >= min_bytes
+                    && stats.stale_bytes as f64 > ratio * stats.bytes as f64
+            }
+            CompactionTrigger::Disabled => false,
+        }
+    }
+
+    /// 把 log 里的一条记录反映到 index 上；被替换掉的那条有快照要用、或者要留多个版本的话留下来
+    fn update_index(&self, key: String, index: Index) {
+        let len = index.offset_end - index.offset_begin;
+        let old = match self.index_map.write().unwrap().entry(key) {
+            IndexEntry::Vacant(entry) => {
+                entry.insert(index);
+                None
+            }
+            IndexEntry::Occupied(mut entry) => {
+                let old = entry.insert(index);
+                let mut retained = self.retained.lock().unwrap();
+                if retained.is_retaining() {
+                    let file = self.log_readers.read().unwrap()[&old.gen].clone();
+                    retained.retain(entry.key(), old.clone(), file);
+                }
+                drop(retained);
+                self.keep_version(entry.key(), &old);
+                Some(old)
+            }
+        };
+
+        let mut stats = self.log_stats.lock().unwrap();
+        stats.records += 1;
+        stats.bytes += len;
+        if let Some(old) = old {
+            stats.stale_bytes += old.offset_end - old.offset_begin;
+        }
+    }
+
+    /// 按版本保留的设置，把 key 被替换掉的这条记下来；按个数保留的话多出来的扔掉，
+    /// 按时间保留的等压缩的时候再说
+    fn keep_version(&self, key: &str, old: &Index) {
+        let keep = match self.options.retention {
+            VersionRetention::Latest => return,
+            VersionRetention::Versions(n) => n.saturating_sub(1),
+            VersionRetention::Window(_) => usize::MAX,
+        };
+        let mut log_versions = self.log_versions.write().unwrap();
+        let versions = log_versions.entry(key.to_owned()).or_default();
+        versions.push(old.clone());
+        if versions.len() > keep {
+            versions.drain(..versions.len() - keep);
+        }
+        if versions.is_empty() {
+            log_versions.remove(key);
+        }
+    }
+
     /*
-    先查看目前文件夹内顺序编号到了多少
-    然后创建文件，按序写入
+    key 留下来的所有版本，从新到旧：index 里最新的那条，log 里被替换掉的，然后 sstable 从新到旧。
+    每个来源里的都比后面来源里的新，直接接起来就是从新到旧的；最后按版本保留的设置截掉
     */
-    fn write_into_sstable(&self, key_item_map : &HashMap<String, Command>) -> Result<()> {
-        let length = self.sstable_path_vec.lock().unwrap().len();
-        let new_file = String::from("sstable_") + &length.to_string() + ".txt";
-
-        let mut new_path = PathBuf::new();
-        new_path.push(self.dir_path.as_ref());
-        new_path.push(&new_file);
-
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(new_path)?;
-        
-        for key in key_item_map.keys() {
-            let command = serde_json::to_string(&key_item_map[key])?;
-            file.write_all(command.as_bytes())?;
-        }
-
-        file.seek(SeekFrom::Start(0))?;
-        let mut buffer = String::new();
-        // 读取整个文件
-        file.read_to_string(&mut buffer)?;
-
-        self.sstable_path_vec.lock().unwrap().push(new_file);
-        Ok(())
+    fn versions(&self, key: &str) -> Result<Vec<Record>> {
+        // 和 scan_range 一样先拿 sstables 再拿 index 的锁，一直拿着：
+        // 压缩换 sstable 和从 log 里拿掉记录是在这两把锁里一起做的，中间插进来会重复或者漏掉
+        let (logs, tables) = {
+            let sstables = self.sstables.read().unwrap();
+            let index_map = self.index_map.read().unwrap();
+            let log_versions = self.log_versions.read().unwrap();
+            let log_readers = self.log_readers.read().unwrap();
+            let older = log_versions.get(key).into_iter().flatten().rev();
+            let logs: Vec<(Index, Arc<File>)> = index_map
+                .get(key)
+                .into_iter()
+                .chain(older)
+                .map(|index| (index.clone(), log_readers[&index.gen].clone()))
+                .collect();
+            (logs, sstables.clone())
+        };
+        let mut records = Vec::new();
+        for (index, file) in logs {
+            let path = log_path(&self.dir_path, index.gen);
+            records.push(record::read_record(&file, &path, index.offset_begin, index.offset_end)?);
+        }
+
+        let hash = bloom::hash(key);
+        for table in tables.iter().rev() {
+            if table.may_contain(hash) != Some(false) {
+                records.extend(table.versions(key)?);
+            }
+        }
+
+        let now = record::now_millis();
+        let retention = self.options.retention;
+        Ok(records
+            .into_iter()
+            .enumerate()
+            .filter(|(position, record)| retention.keeps(*position, record.written_at, now))
+            .map(|(_, record)| record)
+            .collect())
     }
 
-    // 这边做的是一个很暴力的压缩，也就是条数超了就把前面 count 条筛选一下重复的扔掉
-    fn compact(&self, guard:&mut std::sync::MutexGuard<File>) -> Result<()> {
+    /// 打开的时候回放一遍第 gen 个 log，建立 index，返回最后一条完整记录的结尾。
+    /// 有 hint 的话 hint 盖住的那部分就不用读了
+    fn load_index(&self, gen: u64) -> Result<u64> {
+        let path = log_path(&self.dir_path, gen);
+        let mut file = if self.options.read_only {
+            open_data_file_read_only(&path)?
+        } else {
+            open_data_file(&path)?
+        };
+        let len = file.metadata()?.len();
+        let mut offset_end = FILE_HEADER_LEN;
+        if let Some((covered, entries)) = hint::read(&self.dir_path, gen, len)? {
+            for entry in entries {
+                self.update_index(entry.key, Index::new(gen, entry.record_type, entry.seq, entry.begin, entry.end));
+            }
+            offset_end = covered;
+        }
+        file.seek(SeekFrom::Start(offset_end))?;
 
-        guard.seek(SeekFrom::Start(0))?;
-        let mut buffer = String::new();
-        // 读取整个文件
-        guard.read_to_string(&mut buffer)?;
-        let mut indices= serde_json::Deserializer::from_str(&buffer).into_iter::<Command>();
+        let mut records = RecordReader::new(BufReader::new(&file), &path, offset_end, len);
+        for item in &mut records {
+            let (begin, end, record) = item?;
+            self.update_index(record.key, Index::new(gen, record.record_type, record.seq, begin, end));
+            offset_end = end;
+        }
 
-        let mut key_item_map :HashMap<String, Command> = HashMap::new();
+        // 尾部写了一半的记录，直接截掉；只读的时候不动文件，跳过就行
+        if let Some(torn_at) = records.torn_at() {
+            if self.options.read_only {
+                log::warn!("ignoring torn record at offset {} of {}", torn_at, path.display());
+            } else {
+                log::warn!("truncating torn record at offset {} of {}", torn_at, path.display());
+                file.set_len(torn_at)?;
+            }
+        }
+
+        self.log_readers.write().unwrap().insert(gen, Arc::new(file));
+        Ok(offset_end)
+    }
+
+    /// key 最新的那条记录，是删除或者在 now 已经过期的话返回 None
+    fn find(&self, key: &str, now: u64) -> Result<Option<Record>> {
+        Ok(self.newest(key)?.filter(|record| record.is_live(now)))
+    }
+
+    /// key 最新的那条记录，删除和过期了的也算
+    fn newest(&self, key: &str) -> Result<Option<Record>> {
+        match self.search_logs(key)? {
+            Some(record) => Ok(Some(record)),
+            //开始倒序寻找
+            None => self.search_sstables(key),
+        }
+    }
+
+    /// 在 log 里查找 key，没有的话返回 None
+    fn search_logs(&self, key: &str) -> Result<Option<Record>> {
+        let (index, file) = {
+            let index_map = self.index_map.read().unwrap();
+            match index_map.get(key) {
+                None => return Ok(None),
+                Some(index) if index.record_type == RecordType::Remove => {
+                    return Ok(Some(Record { seq: index.seq, ..Record::remove(key.to_owned()) }))
+                }
+                // 拿着 index 的读锁取句柄：后台压缩要先把 log 从 index 里拿掉，才会关掉它的句柄
+                Some(index) => (index.clone(), self.log_readers.read().unwrap()[&index.gen].clone()),
+            }
+        };
 
-        let mut count = 0;
-        let mut offset = 0;
-        while let Some(command) = indices.next() {
-            let command = command?;
-            if count > 2000 { //设定大于2000就做压缩
-                
-                self.restore_rest_file(offset, guard)?;
+        // 文件就算已经被压缩删掉了，拿着的句柄也还能读
+        let path = log_path(&self.dir_path, index.gen);
+        record::read_record(&file, &path, index.offset_begin, index.offset_end).map(Some)
+    }
 
-                self.write_into_sstable(&key_item_map)?;
+    /*
+    按 key 的顺序合并 log 和所有 sstable 里落在 range 里的记录，同一个 key 只要最新的那条，
+    删掉了的跳过。
+    index 里的 key 一次只拿 SCAN_CHUNK 个，拿着锁记下它们在 log 里的位置和当时的 sstable，
+    范围就截到这一段的最后一个 key；这一段走完了再从它后面接着拿，
+    拿锁的时间和占的内存都不会跟着范围变大，只要前几条的话后面也不用拿
+    */
+    fn scan_range(&self, range: (Bound<String>, Bound<String>)) -> impl Iterator<Item = Result<(String, String)>> + Send {
+        let sstables = self.sstables.clone();
+        let index_map = self.index_map.clone();
+        let log_readers = self.log_readers.clone();
+        let (start, end) = range;
+        let mut next_start = Some(start);
+        let chunks = iter::from_fn(move || {
+            let range = (next_start.take()?, end.clone());
+            if range_is_empty(&range) {
+                return None;
+            }
+            // 和压缩一样，先拿 sstables 再拿 index 的锁
+            let sstables = sstables.read().unwrap();
+            let index_map = index_map.read().unwrap();
+            let log_readers = log_readers.read().unwrap();
 
-                return Ok(())
+            let mut logs: Vec<_> = index_map
+                .range(range.clone())
+                .take(SCAN_CHUNK + 1)
+                .map(|(key, index)| {
+                    let file = log_readers[&index.gen].clone();
+                    (key.clone(), Entry::Log { index: index.clone(), file })
+                })
+                .collect();
+            let chunk_end = if logs.len() > SCAN_CHUNK {
+                logs.pop();
+                let last = logs.last().unwrap().0.clone();
+                next_start = Some(Bound::Excluded(last.clone()));
+                Bound::Included(last)
             } else {
-                if &command.action == "set" {
-                    key_item_map.insert(command.key.clone(), command);
-                } else if command.action == "rm" {
-                    let command_key = command.key;
-                    if key_item_map.contains_key(&command_key) {
-                        key_item_map.remove_entry(&command_key);
+                range.1
+            };
+            let chunk = (range.0, chunk_end);
+
+            // 越新的越靠前：先是 log，然后 sstable 从新到旧
+            let mut sources: Vec<Source<Entry>> = vec![Box::new(logs.into_iter().map(Ok))];
+            for table in sstables.iter().rev() {
+                let records = table.scan(chunk.clone());
+                sources.push(Box::new(records.map(|record| record.map(|record| (record.key.clone(), Entry::Table(record))))));
+            }
+            Some(MergeByKey::new(sources))
+        });
+
+        // 过期按开始扫的时间算，扫到一半过期的 key 也照样返回
+        let dir_path = self.dir_path.clone();
+        let now = record::now_millis();
+        // 读出错了就到此为止，后面的段也不用再拿了
+        let mut failed = false;
+        let items = chunks.flatten().map_while(move |item| {
+            if failed {
+                return None;
+            }
+            failed = item.is_err();
+            Some(item)
+        });
+        items.filter_map(move |item| {
+            let (key, entries) = match item {
+                Ok(item) => item,
+                Err(e) => return Some(Err(e)),
+            };
+            let record = match entries.into_iter().next()? {
+                Entry::Log { index, .. } if index.record_type == RecordType::Remove => return None,
+                Entry::Log { index, file } => {
+                    let path = log_path(&dir_path, index.gen);
+                    match record::read_record(&file, &path, index.offset_begin, index.offset_end) {
+                        Ok(record) => record,
+                        Err(e) => return Some(Err(e)),
                     }
                 }
+                Entry::Table(record) => record,
+            };
+            record.is_live(now).then_some(Ok((key, record.value)))
+        })
+    }
+
+    /// 快照读：key 在 seq 时的那条记录。index 里的太新的话找快照留下来的记录，
+    /// 都没有的话就在快照打开时的 sstable 里
+    fn search_at(&self, key: &str, seq: u64, tables: &[Arc<SsTable>]) -> Result<Option<Record>> {
+        let version = {
+            let index_map = self.index_map.read().unwrap();
+            match index_map.get(key) {
+                Some(index) if index.seq <= seq => {
+                    let file = self.log_readers.read().unwrap()[&index.gen].clone();
+                    Some(Version { index: index.clone(), file })
+                }
+                // 压缩拿掉 index 的时候拿着 index 的写锁把记录留下来，这里看到的是一致的
+                _ => self.retained.lock().unwrap().find(key, seq),
+            }
+        };
+        match version {
+            Some(Version { index, .. }) if index.record_type == RecordType::Remove => {
+                Ok(Some(Record::remove(key.to_owned())))
             }
-            count += 1;
-            offset = indices.byte_offset() as u64;
+            Some(Version { index, file }) => {
+                let path = log_path(&self.dir_path, index.gen);
+                record::read_record(&file, &path, index.offset_begin, index.offset_end).map(Some)
+            }
+            None => self.search_tables(tables, key),
+        }
+    }
+
+    /// 在当前的 sstable 里面从新到旧查找 key
+    fn search_sstables(&self, key: &str) -> Result<Option<Record>> {
+        // 拿一份当前 sstable 的快照就放锁，被压缩删掉的文件也还能接着读
+        let tables = self.sstables.read().unwrap().clone();
+        self.search_tables(&tables, key)
+    }
+
+    /// 在 tables 里面从新到旧查找 key，返回找到的第一条记录；过滤器说没有的表直接跳过
+    fn search_tables(&self, tables: &[Arc<SsTable>], key: &str) -> Result<Option<Record>> {
+        let hash = bloom::hash(key);
+        for table in tables.iter().rev() {
+            let may_contain = table.may_contain(hash);
+            if let Some(may_contain) = may_contain {
+                self.bloom_counters.checked(may_contain);
+                if !may_contain {
+                    continue;
+                }
+            }
+            match table.get(key)? {
+                Some(record) => return Ok(Some(record)),
+                None if may_contain.is_some() => self.bloom_counters.false_positive(),
+                None => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /*
+    封存当前的 log，后面的写入进一个新的 log；
+    然后让后台线程把封存的 log 和现在所有的 sstable 合并成一个新的 sstable。
+    上一次压缩还没做完的话就先不压缩，等下次再说
+    */
+    fn start_compaction(&self, guard:&mut MutexGuard<Option<LogWriter>>) -> Result<()> {
+        let mut handle = self.compaction.handle.lock().unwrap();
+        if handle.as_ref().is_some_and(|handle| !handle.is_finished()) {
+            return Ok(());
+        }
+        if let Some(handle) = handle.take() {
+            let _ = handle.join();
+        }
+
+        self.roll_log(guard)?;
+        *self.log_stats.lock().unwrap() = LogStats::default();
+
+        let job = CompactionJob{
+            dir_path: self.dir_path.clone(),
+            index_map: self.index_map.clone(),
+            log_readers: self.log_readers.clone(),
+            sstables: self.sstables.clone(),
+            manifest: self.manifest.clone(),
+            sealed_logs: self.sealed_logs.clone(),
+            tables: self.sstables.read().unwrap().clone(),
+            log_gens: self.sealed_logs.lock().unwrap().clone(),
+            bloom_bits_per_key: self.options.bloom_bits_per_key,
+            last_seq: self.seq.load(Ordering::SeqCst),
+            retained: self.retained.clone(),
+            log_versions: self.log_versions.clone(),
+            retention: self.options.retention,
+        };
+        *handle = Some(thread::Builder::new()
+            .name(String::from("kvs-compaction"))
+            .spawn(move || {
+                if let Err(e) = job.run() {
+                    log::error!("compaction failed: {}", e);
+                }
+            })?);
+        Ok(())
+    }
+
+    /// 封存当前的 log，之后的写入都进一个新的 log；
+    /// 换之前把旧 log 剩下的写入落盘，不然就没人管它们了。封存的 log 不会再变，给它写个 hint
+    fn roll_log(&self, guard:&mut MutexGuard<Option<LogWriter>>) -> Result<()> {
+        if let Some(writer) = guard.as_mut() {
+            if self.options.sync != SyncPolicy::Never {
+                writer.sync()?;
+            }
+        }
+
+        let mut log_gen = self.log_gen.lock().unwrap();
+        let new_gen = *log_gen + 1;
+        // 新 log 读写都打开了才写进 manifest，中途失败的话还接着写旧的 log
+        let writer = open_log_writer(&self.dir_path, new_gen, &self.options)?;
+        let reader = File::open(log_path(&self.dir_path, new_gen))?;
+        {
+            let mut manifest = self.manifest.lock().unwrap();
+            let mut next = manifest.clone();
+            next.logs.push(new_gen);
+            next.store(&self.dir_path)?;
+            *manifest = next;
+        }
+        self.log_readers.write().unwrap().insert(new_gen, Arc::new(reader));
+        **guard = Some(writer);
+
+        let sealed = *log_gen;
+        self.sealed_logs.lock().unwrap().push(sealed);
+        *log_gen = new_gen;
+        drop(log_gen);
+        let log_len = std::mem::replace(&mut *self.offset_begin.lock().unwrap(), FILE_HEADER_LEN);
+
+        // 写不出 hint 也不影响写入，下次打开读 log 就是了
+        if let Err(e) = self.write_hint(sealed, log_len) {
+            log::error!("failed to write hint for log {}: {}", sealed, e);
         }
         Ok(())
     }
     
 }
+
+/// 一次后台压缩：输入是开始时封存的 log 和当时所有的 sstable，
+/// 这些文件都不会再被改动，所以不用拿着写锁
+struct CompactionJob {
+    dir_path: Arc<PathBuf>,
+    index_map: Arc<RwLock<BTreeMap<String, Index>>>,
+    log_readers: Arc<RwLock<HashMap<u64, Arc<File>>>>,
+    sstables: Arc<RwLock<Vec<Arc<SsTable>>>>,
+    manifest: Arc<Mutex<Manifest>>,
+    sealed_logs: Arc<Mutex<Vec<u64>>>,
+    tables: Vec<Arc<SsTable>>,
+    log_gens: Vec<u64>,
+    bloom_bits_per_key: u32,
+    last_seq: u64, // 开始时最后一次写入的 seq，输入里的记录都不会比它新
+    retained: Arc<Mutex<Retained>>,
+    log_versions: Arc<RwLock<HashMap<String, Vec<Index>>>>,
+    retention: VersionRetention,
+}
+
+impl CompactionJob {
+    /*
+    把输入合并成一个新的 sstable：
+    1. 封存的 log 从旧到新读进内存，每个 key 只留最新的一条；log 的大小有压缩的触发条件管着
+    2. 和所有 sstable 按 key 的顺序归并，边归并边写，sstable 再大也不用整个读进内存
+    3. 新 sstable 先写临时文件，落盘后再 rename 成正式的名字
+    4. 新的文件列表写进 manifest，然后换上新的 sstable，最后删掉旧的文件
+    任何一步中途挂掉，重新打开的时候 manifest 里的文件都是一致的，不在里面的会被删掉
+    */
+    fn run(self) -> Result<()> {
+        // 只留最新版本的话每个 key 只要最后一条，不然全留着，后面按设置截
+        let mut logs :BTreeMap<String, Vec<Record>> = BTreeMap::new();
+        for gen in &self.log_gens {
+            for item in record::read_records(&log_path(&self.dir_path, *gen))? {
+                let (_, _, record) = item?;
+                let versions = logs.entry(record.key.clone()).or_default();
+                if self.retention == VersionRetention::Latest {
+                    versions.clear();
+                }
+                versions.push(record);
+            }
+        }
+
+        let logs = logs
+            .into_iter()
+            .flat_map(|(key, versions)| versions.into_iter().rev().map(move |record| Ok((key.clone(), record))));
+        let mut sources: Vec<Source<Record>> = vec![Box::new(logs)];
+        for table in self.tables.iter().rev() {
+            let records = table.scan((Bound::Unbounded, Bound::Unbounded));
+            sources.push(Box::new(records.map(|record| record.map(|record| (record.key.clone(), record)))));
+        }
+
+        let now = record::now_millis();
+        let retention = self.retention;
+        let merged = MergeByKey::new(sources).flat_map(move |item| match item {
+            Err(e) => vec![Err(e)],
+            Ok((key, records)) => retain_versions(key, records, retention, now).into_iter().map(Ok).collect(),
+        });
+
+        let table_gens: Vec<u64> = self.tables.iter().map(|table| table.gen).collect();
+        let gen = table_gens.last().map_or(0, |gen| gen + 1);
+        let table = Arc::new(SsTable::create(&self.dir_path, gen, self.bloom_bits_per_key, merged)?);
+
+        // manifest 换过去这次压缩才算数，在这之前挂掉的话新的 sstable 下次打开会被删掉
+        {
+            let mut manifest = self.manifest.lock().unwrap();
+            let mut next = manifest.clone();
+            next.logs.retain(|gen| !self.log_gens.contains(gen));
+            next.sstables.retain(|gen| !table_gens.contains(gen));
+            next.sstables.push(gen);
+            next.last_seq = next.last_seq.max(self.last_seq);
+            next.store(&self.dir_path)?;
+            *manifest = next;
+        }
+
+        // 先换上新的 sstable，再把封存的 log 从 index 里拿掉，读的时候总能找到
+        let old_tables = {
+            let mut sstables = self.sstables.write().unwrap();
+            let (old_tables, rest) = std::mem::take(&mut *sstables)
+                .into_iter()
+                .partition::<Vec<_>, _>(|table| table_gens.contains(&table.gen));
+            *sstables = rest;
+            sstables.push(table);
+            sstables.sort_by_key(|table| table.gen);
+
+            // 有打开着的快照的话，从 index 里拿掉的记录要留给它们
+            let mut index_map = self.index_map.write().unwrap();
+            let mut retained = self.retained.lock().unwrap();
+            if retained.is_retaining() {
+                let log_readers = self.log_readers.read().unwrap();
+                for (key, index) in index_map.iter().filter(|(_, index)| self.log_gens.contains(&index.gen)) {
+                    retained.retain(key, index.clone(), log_readers[&index.gen].clone());
+                }
+            }
+            index_map.retain(|_, index| !self.log_gens.contains(&index.gen));
+            self.log_versions.write().unwrap().retain(|_, versions| {
+                versions.retain(|index| !self.log_gens.contains(&index.gen));
+                !versions.is_empty()
+            });
+            // 合并过的 log 不能再交给下一次压缩，要和 index 一起换掉
+            self.sealed_logs.lock().unwrap().retain(|gen| !self.log_gens.contains(gen));
+            old_tables
+        };
+        self.log_readers.write().unwrap().retain(|gen, _| !self.log_gens.contains(gen));
+
+        // 新的 manifest 已经生效了，旧文件删不掉也没关系，下次打开的时候会当成多余的文件删掉
+        for old in old_tables {
+            if let Err(e) = old.remove_file() {
+                log::error!("failed to remove sstable {}: {}", old.gen, e);
+            }
+        }
+        for gen in &self.log_gens {
+            if let Err(e) = fs::remove_file(log_path(&self.dir_path, *gen)) {
+                log::error!("failed to remove log {}: {}", gen, e);
+            }
+            if let Err(e) = hint::remove(&self.dir_path, *gen) {
+                log::error!("failed to remove hint for log {}: {}", gen, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/*
+压缩的时候一个 key 的所有记录（从新到旧）里留下哪些：
+1. 最新的那条还有效就留下；过期了的当成删除
+2. 更早的按版本保留的设置留，过期了的值没人看得到，不留
+3. 删除只有在挡住了某个旧 sstable 里的值、或者后面还留着旧版本的时候才需要留下来；
+   挡着旧值是因为旧文件要等新文件生效以后才删，中间挂掉的话还得靠它挡着，下一次合并就可以扔了
+*/
+fn retain_versions(key: String, records: Vec<Record>, retention: VersionRetention, now: u64) -> Vec<Record> {
+    let mut records = records.into_iter();
+    let newest = match records.next() {
+        Some(newest) => newest,
+        None => return Vec::new(),
+    };
+    let mut shadowed = false;
+    let older: Vec<Record> = records
+        .filter(|record| {
+            shadowed |= record.is_live(now);
+            record.record_type == RecordType::Remove || record.is_live(now)
+        })
+        .enumerate()
+        .filter(|(position, record)| retention.keeps(position + 1, record.written_at, now))
+        .map(|(_, record)| record)
+        .collect();
+
+    let newest = if newest.is_live(now) {
+        newest
+    } else if shadowed || !older.is_empty() {
+        Record { seq: newest.seq, written_at: newest.written_at, ..Record::remove(key) }
+    } else {
+        return Vec::new();
+    };
+    std::iter::once(newest).chain(older).collect()
+}
+
+/// 第 gen 个 log 文件的路径
+fn log_path(dir_path: &Path, gen: u64) -> PathBuf {
+    dir_path.join(format!("log_{}.txt", gen))
+}
+
+/// 把打开着的 from 的前 len 个字节复制到新文件 to，落盘以后才返回
+fn copy_file(from: &File, to: &Path, len: u64) -> Result<()> {
+    let mut dest = File::create(to)?;
+    io::copy(&mut from.take(len), &mut dest)?;
+    dest.sync_all()?;
+    Ok(())
+}
+
+/// 拿目录的写锁。flock 的锁跟着打开的文件走，文件关掉或者进程退出就自动放了
+fn lock_dir(dir_path: &Path) -> Result<File> {
+    let path = dir_path.join("LOCK");
+    let file = OpenOptions::new().write(true).create(true).truncate(false).open(&path)?;
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(file),
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+            Err(KvsError::Locked(dir_path.display().to_string()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/*
+没有 manifest 的目录里有哪些数据文件。
+老版本只有一个固定的 log.txt，把它接到已有的 log 后面
+*/
+fn discover_files(dir_path: &Path, read_only: bool) -> Result<Manifest> {
+    let legacy_log = dir_path.join("log.txt");
+    if legacy_log.exists() {
+        if read_only {
+            return Err(KvsError::UnsupportedFormat(String::from(
+                "log.txt must be upgraded by opening the store writable",
+            )));
+        }
+        let gen = list_gens(dir_path, "log_")?.last().map_or(0, |gen| gen + 1);
+        fs::rename(&legacy_log, log_path(dir_path, gen))?;
+    }
+    Ok(Manifest {
+        logs: list_gens(dir_path, "log_")?,
+        sstables: list_gens(dir_path, "sstable_")?,
+        last_seq: 0, // 老版本写的记录都没有 seq
+    })
+}
+
+/// 目录里以 prefix 开头的数据文件的编号，按数字从小到大排，
+/// 不能按字符串排，不然 sstable_10 会排在 sstable_2 前面
+fn list_gens(dir_path: &Path, prefix: &str) -> Result<Vec<u64>> {
+    let mut gen_vec : Vec<u64> = Vec::new();
+    for entry in fs::read_dir(dir_path)? {
+        let file_name = entry?.file_name().to_string_lossy().to_string();
+        let gen = file_name
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(".txt"))
+            .and_then(|gen| gen.parse().ok());
+        if let Some(gen) = gen {
+            gen_vec.push(gen);
+        }
+    }
+    gen_vec.sort_unstable();
+    Ok(gen_vec)
+}
+
+/// 把目录本身落盘，这样里面文件的创建和 rename 才算持久化了
+fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// 打开一个数据文件：新文件写入文件头，旧格式的文件先升级成当前格式
+fn open_data_file(path: &Path) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+
+    match FileFormat::detect(&mut file)? {
+        FileFormat::Empty => record::write_file_header(&mut file)?,
+        FileFormat::Binary(FORMAT_VERSION) => {}
+        // 记录是兼容的，不用重写；但是以后可能写进新的记录，文件头先改成新版本，老版本的程序才会拒绝它
+        format if format.is_readable() => record::bump_version(path)?,
+        format => {
+            drop(file);
+            record::upgrade_file(path, &format)?;
+            file = OpenOptions::new().read(true).append(true).open(path)?;
+        }
+    }
+    Ok(file)
+}
+
+/// 打开第 gen 个 log 准备往后面追加，配置了 FileLayer 的话套上它
+fn open_log_writer(dir_path: &Path, gen: u64, options: &KvStoreOptions) -> Result<LogWriter> {
+    let path = log_path(dir_path, gen);
+    let file = open_data_file(&path)?;
+    let file: Box<dyn LogFile> = match &options.file_layer {
+        Some(layer) => layer.open_log(&path, file)?,
+        None => Box::new(file),
+    };
+    Ok(LogWriter{file, unsynced: 0, broken: false})
+}
+
+/// 只读地打开一个数据文件，旧格式的文件没法在这里升级，直接报错
+fn open_data_file_read_only(path: &Path) -> Result<File> {
+    let mut file = File::open(path)?;
+    match FileFormat::detect(&mut file)? {
+        format if format.is_readable() => Ok(file),
+        _ => Err(KvsError::UnsupportedFormat(format!(
+            "{} must be upgraded by opening the store writable",
+            path.display()
+        ))),
+    }
+}
@@ -0,0 +1,163 @@
+//! The manifest: which data files make up the store.
+//!
+//! `MANIFEST` lists the generations of the live logs and sstables. It is rewritten as a
+//! whole under a temporary name and renamed into place, so after a crash it names
+//! either the files from before a change or the ones from after it. Data files it does
+//! not name are leftovers of an interrupted compaction or log roll.
+//!
+//! ```text
+//! | magic "KVSM" | version: u32 | log count: u32 | log gen: u64 ... |
+//! | sstable count: u32 | sstable gen: u64 ... | last seq: u64 | crc32: u32 |
+//! ```
+//!
+//! The last log is the one being written to. `last seq` is at least the sequence
+//! number of every record in the sstables; version 1 manifests do not have it.
+
+use super::{sync_dir, KvsError, Result};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+const MANIFEST_NAME: &str = "MANIFEST";
+const MANIFEST_MAGIC: &[u8; 4] = b"KVSM";
+const MANIFEST_VERSION: u32 = 2;
+
+/// The live generations, oldest first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    pub logs: Vec<u64>,
+    pub sstables: Vec<u64>,
+    pub last_seq: u64,
+}
+
+impl Manifest {
+    /// Read the manifest of the store in `dir_path`. `None` if the store has none yet,
+    /// i.e. it was written by an older release or is new.
+    pub fn load(dir_path: &Path) -> Result<Option<Manifest>> {
+        let path = dir_path.join(MANIFEST_NAME);
+        let buffer = match fs::read(&path) {
+            Ok(buffer) => buffer,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // manifest 是整个 rename 进来的，读出来不对只能是坏了
+        decode(&buffer).map(Some).ok_or_else(|| KvsError::Corruption {
+            file: path.display().to_string(),
+            offset: 0,
+        })
+    }
+
+    /// Durably replace the manifest of the store in `dir_path` with this one.
+    pub fn store(&self, dir_path: &Path) -> Result<()> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(MANIFEST_MAGIC);
+        buffer.extend_from_slice(&MANIFEST_VERSION.to_le_bytes());
+        for gens in [&self.logs, &self.sstables] {
+            buffer.extend_from_slice(&(gens.len() as u32).to_le_bytes());
+            for gen in gens {
+                buffer.extend_from_slice(&gen.to_le_bytes());
+            }
+        }
+        buffer.extend_from_slice(&self.last_seq.to_le_bytes());
+        let checksum = crc32fast::hash(&buffer);
+        buffer.extend_from_slice(&checksum.to_le_bytes());
+
+        let path = dir_path.join(MANIFEST_NAME);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&tmp_path, &path)?;
+        sync_dir(dir_path)
+    }
+
+    /// Delete the files in `dir_path` that are not part of the store: data files and
+    /// hints of generations the manifest does not list, and temporary files.
+    pub fn remove_stray_files(&self, dir_path: &Path) -> Result<()> {
+        for entry in fs::read_dir(dir_path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let gen_of = |prefix: &str, suffix: &str| {
+                name.strip_prefix(prefix)
+                    .and_then(|rest| rest.strip_suffix(suffix))
+                    .and_then(|gen| gen.parse::<u64>().ok())
+            };
+            let stray = if is_temp_file(&name) {
+                true
+            } else if let Some(gen) = gen_of("log_", ".txt").or_else(|| gen_of("log_", ".hint")) {
+                !self.logs.contains(&gen)
+            } else if let Some(gen) = gen_of("sstable_", ".txt") {
+                !self.sstables.contains(&gen)
+            } else {
+                false
+            };
+            if stray {
+                log::warn!("removing stray file {}", entry.path().display());
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Delete the temporary files left behind by writes that never finished.
+pub(crate) fn remove_temp_files(dir_path: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir_path)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if is_temp_file(&name) {
+            log::warn!("removing stray file {}", entry.path().display());
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn decode(buffer: &[u8]) -> Option<Manifest> {
+    let (body, checksum) = buffer.split_at_checked(buffer.len().checked_sub(4)?)?;
+    if crc32fast::hash(body).to_le_bytes() != checksum || body.get(..4)? != MANIFEST_MAGIC {
+        return None;
+    }
+    let mut rest = &body[4..];
+    let version = take_u32(&mut rest)?;
+    if version != 1 && version != MANIFEST_VERSION {
+        return None;
+    }
+
+    let mut gens = [Vec::new(), Vec::new()];
+    for list in &mut gens {
+        let count = take_u32(&mut rest)? as usize;
+        let (list_bytes, tail) = rest.split_at_checked(count.checked_mul(8)?)?;
+        *list = list_bytes
+            .chunks_exact(8)
+            .map(|gen| u64::from_le_bytes(gen.try_into().unwrap()))
+            .collect();
+        rest = tail;
+    }
+    let last_seq = match version {
+        1 => 0,
+        _ => {
+            let (field, tail) = rest.split_at_checked(8)?;
+            rest = tail;
+            u64::from_le_bytes(field.try_into().unwrap())
+        }
+    };
+    if !rest.is_empty() {
+        return None;
+    }
+    let [logs, sstables] = gens;
+    Some(Manifest { logs, sstables, last_seq })
+}
+
+fn take_u32(rest: &mut &[u8]) -> Option<u32> {
+    let (field, tail) = rest.split_at_checked(4)?;
+    *rest = tail;
+    Some(u32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+}
+
+/// Temporary files are only ever renamed into place once complete; any left over
+/// belong to a write that never finished.
+fn is_temp_file(name: &str) -> bool {
+    name.ends_with(".tmp") || name.ends_with(".upgrading")
+}
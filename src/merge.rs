@@ -0,0 +1,58 @@
+//! Merging sorted sources of records by key.
+
+use super::Result;
+use std::iter::Peekable;
+
+/// One sorted source of `(key, item)` pairs. Items with the same key are newest first.
+pub(crate) type Source<T> = Box<dyn Iterator<Item = Result<(String, T)>> + Send>;
+
+/// Walks several sorted sources in key order. Sources are given newest first; for every
+/// key it yields all the items found for it, newest first.
+pub(crate) struct MergeByKey<T> {
+    sources: Vec<Peekable<Source<T>>>,
+    failed: bool,
+}
+
+impl<T> MergeByKey<T> {
+    pub fn new(sources: Vec<Source<T>>) -> MergeByKey<T> {
+        MergeByKey {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+            failed: false,
+        }
+    }
+}
+
+impl<T> Iterator for MergeByKey<T> {
+    type Item = Result<(String, Vec<T>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        // 哪个来源读出错了就先报出来，后面也不用再走了
+        for source in &mut self.sources {
+            if let Some(Err(_)) = source.peek() {
+                self.failed = true;
+                return source.next().map(|item| item.map(|(key, item)| (key, vec![item])));
+            }
+        }
+
+        let key = self
+            .sources
+            .iter_mut()
+            .filter_map(|source| match source.peek() {
+                Some(Ok((key, _))) => Some(key),
+                _ => None,
+            })
+            .min()?
+            .clone();
+
+        let mut items = Vec::new();
+        for source in &mut self.sources {
+            while let Some(Ok((_, item))) = source.next_if(|next| matches!(next, Ok((other, _)) if *other == key)) {
+                items.push(item);
+            }
+        }
+        Some(Ok((key, items)))
+    }
+}
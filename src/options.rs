@@ -0,0 +1,186 @@
+//! Tuning knobs for `KvStore::open_with`.
+
+use super::{FileLayer, KvsError};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// When the store merges its logs and sstables.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionTrigger {
+    /// Compact once the logs hold more than this many records.
+    RecordCount(u64),
+    /// Compact once at least `min_bytes` of the logs are overwritten or removed data
+    /// and that is more than `ratio` of the logs.
+    StaleRatio {
+        /// Fraction of stale bytes, between 0 and 1.
+        ratio: f64,
+        /// Smallest amount of stale bytes worth a compaction.
+        min_bytes: u64,
+    },
+    /// Never compact.
+    Disabled,
+}
+
+/// When writes are flushed to stable storage.
+///
+/// A write returns once it has reached the point the policy promises; anything after
+/// the last sync may be lost on power failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Leave it to the operating system.
+    Never,
+    /// Sync the log before every write returns.
+    Always,
+    /// Sync the log once n writes have piled up since the last sync.
+    EveryN(u64),
+    /// Sync the log from a background thread at this interval.
+    Interval(Duration),
+}
+
+/// Parses `never`, `always`, `every:<writes>` or `interval:<milliseconds>`.
+impl FromStr for SyncPolicy {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<SyncPolicy, KvsError> {
+        let invalid = || KvsError::InvalidArgument(format!("invalid sync policy: {}", s));
+        let number = |n: &str| n.parse::<u64>().ok().filter(|n| *n > 0).ok_or_else(invalid);
+        match s.split_once(':') {
+            None if s == "never" => Ok(SyncPolicy::Never),
+            None if s == "always" => Ok(SyncPolicy::Always),
+            Some(("every", n)) => Ok(SyncPolicy::EveryN(number(n)?)),
+            Some(("interval", ms)) => Ok(SyncPolicy::Interval(Duration::from_millis(number(ms)?))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Which older versions of a key the store keeps for `KvsEngine::history` and
+/// `KvsEngine::get_at`. Removals count as versions. The newest version is always kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionRetention {
+    /// Only the newest version.
+    Latest,
+    /// The newest n versions.
+    Versions(usize),
+    /// Every version written within this long.
+    Window(Duration),
+}
+
+impl VersionRetention {
+    /// Whether the version at `position`, 0 being the newest, written at `written_at`
+    /// milliseconds since the Unix epoch, is still kept at `now`.
+    pub(crate) fn keeps(&self, position: usize, written_at: u64, now: u64) -> bool {
+        position == 0
+            || match *self {
+                VersionRetention::Latest => false,
+                VersionRetention::Versions(n) => position < n,
+                VersionRetention::Window(window) => {
+                    written_at.saturating_add(window.as_millis() as u64) >= now
+                }
+            }
+    }
+}
+
+/// Parses `latest`, `versions:<n>` or `window:<seconds>`.
+impl FromStr for VersionRetention {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<VersionRetention, KvsError> {
+        let invalid = || KvsError::InvalidArgument(format!("invalid version retention: {}", s));
+        let number = |n: &str| n.parse::<u64>().ok().filter(|n| *n > 0).ok_or_else(invalid);
+        match s.split_once(':') {
+            None if s == "latest" => Ok(VersionRetention::Latest),
+            Some(("versions", n)) => Ok(VersionRetention::Versions(number(n)? as usize)),
+            Some(("window", secs)) => Ok(VersionRetention::Window(Duration::from_secs(number(secs)?))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Options for opening a `KvStore`.
+///
+/// ```no_run
+/// # use kvs::{CompactionTrigger, KvStore, KvStoreOptions, SyncPolicy};
+/// let options = KvStoreOptions::new()
+///     .compaction(CompactionTrigger::RecordCount(10_000))
+///     .max_log_size(64 << 20)
+///     .sync(SyncPolicy::Always);
+/// let store = KvStore::open_with("data", options)?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(crate) compaction: CompactionTrigger,
+    pub(crate) max_log_size: u64,
+    pub(crate) sync: SyncPolicy,
+    pub(crate) read_only: bool,
+    pub(crate) bloom_bits_per_key: u32,
+    pub(crate) retention: VersionRetention,
+    pub(crate) file_layer: Option<Arc<dyn FileLayer>>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            compaction: CompactionTrigger::RecordCount(2000),
+            max_log_size: u64::MAX,
+            sync: SyncPolicy::Never,
+            read_only: false,
+            bloom_bits_per_key: 10,
+            retention: VersionRetention::Latest,
+            file_layer: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// The options `KvStore::open` uses.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// When to compact. Defaults to `CompactionTrigger::RecordCount(2000)`.
+    pub fn compaction(mut self, trigger: CompactionTrigger) -> KvStoreOptions {
+        self.compaction = trigger;
+        self
+    }
+
+    /// Start a new log file once the current one reaches `bytes`. Unlimited by default.
+    pub fn max_log_size(mut self, bytes: u64) -> KvStoreOptions {
+        self.max_log_size = bytes;
+        self
+    }
+
+    /// When to sync writes to disk. Defaults to `SyncPolicy::Never`.
+    pub fn sync(mut self, policy: SyncPolicy) -> KvStoreOptions {
+        self.sync = policy;
+        self
+    }
+
+    /// Open without modifying anything on disk; writes fail with `KvsError::ReadOnly`.
+    pub fn read_only(mut self, read_only: bool) -> KvStoreOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Size of the Bloom filter written with every new sstable, in bits per key. More bits
+    /// make a lookup for an absent key less likely to read the table; 0 writes no filter.
+    /// Defaults to 10, which lets about 1% of such lookups through.
+    pub fn bloom_bits_per_key(mut self, bits: u32) -> KvStoreOptions {
+        self.bloom_bits_per_key = bits;
+        self
+    }
+
+    /// Which older versions of every key to keep. Defaults to `VersionRetention::Latest`.
+    pub fn retention(mut self, retention: VersionRetention) -> KvStoreOptions {
+        self.retention = retention;
+        self
+    }
+
+    /// Route log writes through `layer`, e.g. to inject faults in tests.
+    pub fn file_layer(mut self, layer: Arc<dyn FileLayer>) -> KvStoreOptions {
+        self.file_layer = Some(layer);
+        self
+    }
+}
@@ -0,0 +1,679 @@
+//! On-disk record format shared by the log and the sstable files.
+//!
+//! Every file starts with an 8 byte header: the magic `KVSB` followed by the
+//! format version as a little-endian `u32`. Records follow back to back, each
+//! one a fixed-size header and the raw payload:
+//!
+//! ```text
+//! | crc32: u32 | type: u8 | key length: u32 | value length: u32 | key | value |
+//! ```
+//!
+//! The checksum covers everything after itself. A record that is cut short or
+//! fails its checksum at the very end of a file is a torn write from an unclean
+//! shutdown; anywhere else it is corruption. A record whose length reaches past the
+//! end of the file counts as cut short only if no complete record follows it.
+//!
+//! A set that expires is stored with type 4 and its value prefixed by the expiry
+//! time, in milliseconds since the Unix epoch as a little-endian `u64`. Once that
+//! time has passed the key reads as absent.
+//!
+//! Records carry the sequence number of the write that made them. A type byte with
+//! the high bit set means the value starts with that number as a little-endian `u64`,
+//! in front of any expiry time. Records written before sequence numbers have sequence
+//! number 0. Bit 0x40 means the time of the write, in milliseconds since the Unix
+//! epoch, follows the sequence number.
+//!
+//! A batch of writes that must be applied together is framed as one record of
+//! type 3 with an empty key, whose value is the records of the batch encoded back
+//! to back. Its checksum covers them all, so after a crash the batch is replayed
+//! whole or dropped whole as a torn tail. Batches only appear in logs.
+//!
+//! Sstables group their records into blocks and end with an index of the
+//! blocks, see the `sstable` module.
+//!
+//! Version 3 added expiring sets, sequence numbers and write times. Version 2 records
+//! are read as they are; a version 2 file opened for writing only has its header
+//! bumped, so that older releases refuse it once it holds version 3 records.
+//!
+//! Version 1 files have no checksum. Files written by even older releases hold
+//! a stream of JSON `Command`s instead. `FileFormat::detect` recognizes both and
+//! `upgrade_file` rewrites them in place.
+
+use super::{KvsError, Result};
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Magic bytes at the start of every binary file.
+pub(crate) const MAGIC: &[u8; 4] = b"KVSB";
+/// Version of the format written by this build.
+pub(crate) const FORMAT_VERSION: u32 = 3;
+/// Oldest version whose records this build reads as they are, see `FileFormat::is_readable`.
+pub(crate) const COMPATIBLE_VERSION: u32 = 2;
+/// Length of the file header, i.e. the offset of the first record.
+pub(crate) const FILE_HEADER_LEN: u64 = 8;
+
+/// Length of the record header without the checksum, which is all version 1 had.
+const V1_RECORD_HEADER_LEN: usize = 9;
+const CHECKSUM_LEN: usize = 4;
+/// Type byte of a batch frame. It is not a `RecordType`: readers unpack the frame
+/// and yield the records inside.
+const BATCH_TYPE: u8 = 3;
+/// Type byte of a set with an expiry time. Decoded it is a `RecordType::Set` with
+/// `expires_at`.
+const EXPIRING_SET_TYPE: u8 = 4;
+/// Set in the type byte of records whose value starts with a sequence number.
+const SEQ_FLAG: u8 = 0x80;
+/// Set in the type byte of records that carry the time of their write.
+const TIME_FLAG: u8 = 0x40;
+
+/// The kind of a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RecordType {
+    Set = 1,
+    Remove = 2,
+}
+
+impl RecordType {
+    pub fn from_u8(byte: u8) -> Option<RecordType> {
+        match byte {
+            1 => Some(RecordType::Set),
+            2 => Some(RecordType::Remove),
+            _ => None,
+        }
+    }
+}
+
+/// A decoded record. `value` is empty for removals.
+#[derive(Debug, Clone)]
+pub(crate) struct Record {
+    pub record_type: RecordType,
+    pub key: String,
+    pub value: String,
+    /// When a set stops being visible, in milliseconds since the Unix epoch.
+    pub expires_at: Option<u64>,
+    /// Sequence number of the write, assigned when it is appended to the log.
+    pub seq: u64,
+    /// When the write was appended to the log, in milliseconds since the Unix epoch;
+    /// 0 if that is not known.
+    pub written_at: u64,
+}
+
+impl Record {
+    pub fn set(key: String, value: String) -> Record {
+        Record {
+            record_type: RecordType::Set,
+            key,
+            value,
+            expires_at: None,
+            seq: 0,
+            written_at: 0,
+        }
+    }
+
+    /// A set that is visible until `expires_at`, see `now_millis`.
+    pub fn set_expiring(key: String, value: String, expires_at: u64) -> Record {
+        Record {
+            expires_at: Some(expires_at),
+            ..Record::set(key, value)
+        }
+    }
+
+    pub fn remove(key: String) -> Record {
+        Record {
+            record_type: RecordType::Remove,
+            key,
+            value: String::new(),
+            expires_at: None,
+            seq: 0,
+            written_at: 0,
+        }
+    }
+
+    /// Whether this is a set that has not expired at `now`.
+    pub fn is_live(&self, now: u64) -> bool {
+        self.record_type == RecordType::Set && self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+
+    /// Serialize the record, header included.
+    pub fn encode(&self) -> Vec<u8> {
+        let key = self.key.as_bytes();
+        let value = self.value.as_bytes();
+        let mut prefix = self.seq.to_le_bytes().to_vec();
+        prefix.extend_from_slice(&self.written_at.to_le_bytes());
+        let record_type = match self.expires_at {
+            Some(expires_at) => {
+                prefix.extend_from_slice(&expires_at.to_le_bytes());
+                EXPIRING_SET_TYPE
+            }
+            None => self.record_type as u8,
+        };
+        let value_len = prefix.len() + value.len();
+        let mut buf = Vec::with_capacity(CHECKSUM_LEN + V1_RECORD_HEADER_LEN + key.len() + value_len);
+        buf.extend_from_slice(&[0u8; CHECKSUM_LEN]);
+        buf.push(record_type | SEQ_FLAG | TIME_FLAG);
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(value_len as u32).to_le_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(&prefix);
+        buf.extend_from_slice(value);
+        let checksum = crc32fast::hash(&buf[CHECKSUM_LEN..]);
+        buf[..CHECKSUM_LEN].copy_from_slice(&checksum.to_le_bytes());
+        buf
+    }
+
+    /// Encode `records` as one batch frame. Also returns the `[begin, end)` of every
+    /// record within the frame, where `read_record` can later find it on its own.
+    pub fn encode_batch(records: &[Record]) -> (Vec<u8>, Vec<(u64, u64)>) {
+        let header_len = CHECKSUM_LEN + V1_RECORD_HEADER_LEN;
+        let mut buf = vec![0u8; header_len];
+        let mut spans = Vec::with_capacity(records.len());
+        for record in records {
+            let begin = buf.len() as u64;
+            buf.extend_from_slice(&record.encode());
+            spans.push((begin, buf.len() as u64));
+        }
+        let value_len = (buf.len() - header_len) as u32;
+        buf[CHECKSUM_LEN] = BATCH_TYPE;
+        buf[CHECKSUM_LEN + 1..CHECKSUM_LEN + 5].copy_from_slice(&0u32.to_le_bytes());
+        buf[CHECKSUM_LEN + 5..header_len].copy_from_slice(&value_len.to_le_bytes());
+        let checksum = crc32fast::hash(&buf[CHECKSUM_LEN..]);
+        buf[..CHECKSUM_LEN].copy_from_slice(&checksum.to_le_bytes());
+        (buf, spans)
+    }
+
+    /// Decode the single record stored in `buf`, which was read from `path` at `offset`.
+    pub fn decode(mut buf: &[u8], path: &Path, offset: u64) -> Result<Record> {
+        let len = buf.len() as u64;
+        let raw = read_raw(&mut buf, FORMAT_VERSION, len)?;
+        match raw {
+            RawRecord::Complete { body, checksum_ok: true, .. } if buf.is_empty() => {
+                body.into_record(path, offset)
+            }
+            _ => Err(corruption(path, offset)),
+        }
+    }
+}
+
+fn corruption(path: &Path, offset: u64) -> KvsError {
+    KvsError::Corruption {
+        file: path.display().to_string(),
+        offset,
+    }
+}
+
+/// A record as laid out on disk, before its type and payload are validated.
+struct RawBody {
+    record_type: u8,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl RawBody {
+    /// Unpack the records of a batch frame that starts at `offset`, with their offsets.
+    fn into_batch(self, path: &Path, offset: u64) -> Result<Vec<(u64, u64, Record)>> {
+        let mut pos = offset + (CHECKSUM_LEN + V1_RECORD_HEADER_LEN) as u64;
+        let mut rest = &self.value[..];
+        let mut records = Vec::new();
+        // 外面的校验和已经对上了，里面再出问题只能是坏了
+        while !rest.is_empty() {
+            let remaining = rest.len() as u64;
+            match read_raw(&mut rest, FORMAT_VERSION, remaining)? {
+                RawRecord::Complete { body, len, checksum_ok: true } => {
+                    records.push((pos, pos + len, body.into_record(path, pos)?));
+                    pos += len;
+                }
+                _ => return Err(corruption(path, pos)),
+            }
+        }
+        Ok(records)
+    }
+
+    fn into_record(self, path: &Path, offset: u64) -> Result<Record> {
+        let damaged = || corruption(path, offset);
+        let mut value = &self.value[..];
+        let mut take_u64 = || {
+            let (field, rest) = value.split_at_checked(8).ok_or_else(damaged)?;
+            value = rest;
+            Ok::<_, KvsError>(u64::from_le_bytes(field.try_into().unwrap()))
+        };
+        let seq = if self.record_type & SEQ_FLAG != 0 { take_u64()? } else { 0 };
+        let written_at = if self.record_type & TIME_FLAG != 0 { take_u64()? } else { 0 };
+        let (record_type, expires_at) = match self.record_type & !(SEQ_FLAG | TIME_FLAG) {
+            EXPIRING_SET_TYPE => (RecordType::Set, Some(take_u64()?)),
+            byte => (RecordType::from_u8(byte).ok_or_else(damaged)?, None),
+        };
+        Ok(Record {
+            record_type,
+            key: String::from_utf8(self.key)?,
+            value: String::from_utf8(value.to_vec())?,
+            expires_at,
+            seq,
+            written_at,
+        })
+    }
+}
+
+enum RawRecord {
+    /// Clean end of file.
+    End,
+    /// The file ends in the middle of the record.
+    Incomplete,
+    /// The length fields reach past the end of the file, but complete records follow.
+    Damaged,
+    /// The whole record was read; `len` is its size on disk.
+    Complete {
+        body: RawBody,
+        len: u64,
+        checksum_ok: bool,
+    },
+}
+
+/// Read the next record. `remaining` is the number of bytes left in the file and
+/// keeps a garbage length field from turning into a huge allocation.
+fn read_raw<R: Read>(reader: &mut R, version: u32, remaining: u64) -> Result<RawRecord> {
+    let checksum_len = if version >= 2 { CHECKSUM_LEN } else { 0 };
+    let header_len = checksum_len + V1_RECORD_HEADER_LEN;
+
+    let mut header = [0u8; CHECKSUM_LEN + V1_RECORD_HEADER_LEN];
+    let header = &mut header[..header_len];
+    let read = read_full(reader, header)?;
+    if read == 0 {
+        return Ok(RawRecord::End);
+    } else if read < header_len {
+        return Ok(RawRecord::Incomplete);
+    }
+
+    let field = |at: usize| {
+        u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]])
+    };
+    let key_len = field(checksum_len + 1) as u64;
+    let value_len = field(checksum_len + 5) as u64;
+    let len = header_len as u64 + key_len + value_len;
+    if len > remaining {
+        // 写到一半的记录后面什么都没有；后面还有完整的记录的话是长度字段坏了，
+        // 当成 torn tail 截掉就把确认过的写入也删了
+        let mut rest = vec![0u8; remaining.saturating_sub(header_len as u64) as usize];
+        let read = read_full(reader, &mut rest)?;
+        if checksum_len > 0 && hides_records(&rest[..read], header[checksum_len] == BATCH_TYPE) {
+            return Ok(RawRecord::Damaged);
+        }
+        return Ok(RawRecord::Incomplete);
+    }
+
+    let mut key = vec![0u8; key_len as usize];
+    let mut value = vec![0u8; value_len as usize];
+    if read_full(reader, &mut key)? < key.len() || read_full(reader, &mut value)? < value.len() {
+        return Ok(RawRecord::Incomplete);
+    }
+
+    let checksum_ok = checksum_len == 0 || {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[checksum_len..]);
+        hasher.update(&key);
+        hasher.update(&value);
+        hasher.finalize() == field(0)
+    };
+
+    Ok(RawRecord::Complete {
+        body: RawBody {
+            record_type: header[checksum_len],
+            key,
+            value,
+        },
+        len,
+        checksum_ok,
+    })
+}
+
+/*
+长度超出文件末尾的那条记录后面的字节里，有没有完整、校验和对得上的记录。
+一个字节一个字节地往后找，找到一条就跳过它接着找。
+写到一半的 batch 里已经写完的记录本来就是完整的，它们的 seq 都一样，不算
+*/
+fn hides_records(rest: &[u8], batch: bool) -> bool {
+    let mut batch_seq = None;
+    let mut pos = 0;
+    while pos < rest.len() {
+        match complete_record(&rest[pos..]) {
+            None => pos += 1,
+            Some((len, record_type, value)) => {
+                let seq = match value.get(..8) {
+                    Some(seq) if batch && record_type & SEQ_FLAG != 0 && record_type != BATCH_TYPE => seq,
+                    _ => return true,
+                };
+                if *batch_seq.get_or_insert(seq) != seq {
+                    return true;
+                }
+                pos += len;
+            }
+        }
+    }
+    false
+}
+
+/// `buf` 开头如果是一条完整、校验和对得上的记录，返回它的长度、类型和值
+fn complete_record(buf: &[u8]) -> Option<(usize, u8, &[u8])> {
+    let header_len = CHECKSUM_LEN + V1_RECORD_HEADER_LEN;
+    let header = buf.get(..header_len)?;
+    let field = |at: usize| u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]]) as usize;
+    let value_begin = header_len.checked_add(field(CHECKSUM_LEN + 1))?;
+    let len = value_begin.checked_add(field(CHECKSUM_LEN + 5))?;
+    let body = buf.get(CHECKSUM_LEN..len)?;
+    (crc32fast::hash(body) == field(0) as u32).then(|| (len, header[CHECKSUM_LEN], &buf[value_begin..len]))
+}
+
+/// Like `read_exact`, but reports how much was read instead of failing at end of file.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Iterates over the records of a file, yielding each record with its `[begin, end)` offsets.
+///
+/// Iteration stops early at a torn tail, whose offset is then available from `torn_at`.
+/// A damaged record that is followed by more data yields `KvsError::Corruption`.
+pub(crate) struct RecordReader<R: Read> {
+    reader: R,
+    path: PathBuf,
+    version: u32,
+    pos: u64,
+    len: u64,
+    torn_at: Option<u64>,
+    batch: VecDeque<(u64, u64, Record)>, // 读到 batch 的时候，里面还没交出去的记录
+}
+
+impl<R: Read> RecordReader<R> {
+    /// `pos` is the offset `reader` is currently positioned at and `len` the length of the file.
+    pub fn new(reader: R, path: &Path, pos: u64, len: u64) -> RecordReader<R> {
+        RecordReader::with_version(reader, path, FORMAT_VERSION, pos, len)
+    }
+
+    fn with_version(reader: R, path: &Path, version: u32, pos: u64, len: u64) -> RecordReader<R> {
+        RecordReader {
+            reader,
+            path: path.to_owned(),
+            version,
+            pos,
+            len,
+            torn_at: None,
+            batch: VecDeque::new(),
+        }
+    }
+
+    /// Offset of the torn record at the end of the file, if iteration stopped at one.
+    pub fn torn_at(&self) -> Option<u64> {
+        self.torn_at
+    }
+}
+
+impl<R: Read> Iterator for RecordReader<R> {
+    type Item = Result<(u64, u64, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(item) = self.batch.pop_front() {
+            return Some(Ok(item));
+        }
+        if self.torn_at.is_some() {
+            return None;
+        }
+        let begin = self.pos;
+        let remaining = self.len.saturating_sub(begin);
+        match read_raw(&mut self.reader, self.version, remaining) {
+            Ok(RawRecord::End) => None,
+            Ok(RawRecord::Incomplete) => {
+                self.torn_at = Some(begin);
+                None
+            }
+            Ok(RawRecord::Damaged) => {
+                self.pos = self.len;
+                Some(Err(corruption(&self.path, begin)))
+            }
+            Ok(RawRecord::Complete { body, len, checksum_ok }) => {
+                self.pos += len;
+                if checksum_ok && body.record_type == BATCH_TYPE && self.version >= 2 {
+                    match body.into_batch(&self.path, begin) {
+                        Ok(records) => {
+                            self.batch = records.into();
+                            self.next()
+                        }
+                        Err(e) => Some(Err(e)),
+                    }
+                } else if checksum_ok {
+                    Some(body.into_record(&self.path, begin).map(|record| (begin, self.pos, record)))
+                } else if self.pos == self.len {
+                    self.torn_at = Some(begin);
+                    None
+                } else {
+                    Some(Err(corruption(&self.path, begin)))
+                }
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Find the records of `key` among the records in `buf`, which are sorted by key and
+/// were read from `path` at `offset`. Only the matching records are decoded and checksummed.
+pub(crate) fn find_sorted(buf: &[u8], path: &Path, offset: u64, key: &str) -> Result<Vec<Record>> {
+    let mut found = Vec::new();
+    let header_len = CHECKSUM_LEN + V1_RECORD_HEADER_LEN;
+    let mut pos = 0;
+    while pos < buf.len() {
+        let damaged = || corruption(path, offset + pos as u64);
+        let header = buf.get(pos..pos + header_len).ok_or_else(damaged)?;
+        let field = |at: usize| {
+            u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]]) as usize
+        };
+        let key_begin = pos + header_len;
+        let key_end = key_begin + field(CHECKSUM_LEN + 1);
+        let end = key_end + field(CHECKSUM_LEN + 5);
+        if end > buf.len() {
+            return Err(damaged());
+        }
+        match buf[key_begin..key_end].cmp(key.as_bytes()) {
+            Ordering::Equal => found.push(Record::decode(&buf[pos..end], path, offset + pos as u64)?),
+            Ordering::Greater => break,
+            Ordering::Less => {}
+        }
+        pos = end;
+    }
+    Ok(found)
+}
+
+/// The current time in milliseconds since the Unix epoch, as used for `Record::expires_at`.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_millis() as u64
+}
+
+/// Iterate over every record of the data file at `path`.
+pub(crate) fn read_records(path: &Path) -> Result<RecordReader<BufReader<File>>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(FILE_HEADER_LEN))?;
+    Ok(RecordReader::new(BufReader::new(file), path, FILE_HEADER_LEN, len))
+}
+
+/// Read the record stored at `[begin, end)` of `file`, which lives at `path`.
+///
+/// The read does not move the file cursor, so any number of threads can share `file`.
+pub(crate) fn read_record(file: &File, path: &Path, begin: u64, end: u64) -> Result<Record> {
+    let mut buffer = vec![0u8; (end - begin) as usize];
+    read_exact_at(file, &mut buffer, begin)?;
+    Record::decode(&buffer, path, begin)
+}
+
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The format of a data file, as found on disk.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum FileFormat {
+    /// Nothing written yet, not even the header.
+    Empty,
+    /// Binary records of the given format version, see the module documentation.
+    Binary(u32),
+    /// JSON `Command`s written by older releases.
+    LegacyJson,
+}
+
+impl FileFormat {
+    /// Whether the file can be read without `upgrade_file` rewriting it first.
+    pub fn is_readable(&self) -> bool {
+        match self {
+            FileFormat::Empty => true,
+            FileFormat::Binary(version) => *version >= COMPATIBLE_VERSION,
+            FileFormat::LegacyJson => false,
+        }
+    }
+
+    /// Inspect the beginning of `file`. Leaves the cursor at an unspecified position.
+    pub fn detect(file: &mut File) -> Result<FileFormat> {
+        file.seek(SeekFrom::Start(0))?;
+        let mut header = [0u8; FILE_HEADER_LEN as usize];
+        let read = read_full(file, &mut header)?;
+        if read == 0 {
+            return Ok(FileFormat::Empty);
+        }
+        if read == header.len() && &header[..4] == MAGIC {
+            let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+            if version > FORMAT_VERSION {
+                return Err(KvsError::UnsupportedFormat(format!(
+                    "file format version {} is newer than {}",
+                    version, FORMAT_VERSION
+                )));
+            }
+            return Ok(FileFormat::Binary(version));
+        }
+        match header[..read].iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') | None => Ok(FileFormat::LegacyJson),
+            Some(_) => Err(KvsError::UnsupportedFormat(String::from(
+                "unrecognized file header",
+            ))),
+        }
+    }
+}
+
+/// Write the file header to an empty file.
+pub(crate) fn write_file_header<W: Write>(writer: &mut W) -> Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    Ok(())
+}
+
+/// Change the header of the readable file at `path` to the current format version.
+pub(crate) fn bump_version(path: &Path) -> Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+    file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    file.sync_data()?;
+    log::info!("bumped {} to format version {}", path.display(), FORMAT_VERSION);
+    Ok(())
+}
+
+/// The JSON representation used by older releases.
+#[derive(Deserialize)]
+struct LegacyCommand {
+    action: String,
+    key: String,
+    value: String,
+}
+
+/// Read every record of a file in an older `format`.
+fn read_old_records(path: &Path, format: &FileFormat) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    match format {
+        FileFormat::LegacyJson => {
+            let buffer = fs::read_to_string(path)?;
+            for command in serde_json::Deserializer::from_str(&buffer).into_iter::<LegacyCommand>() {
+                // 写到一半的最后一条命令和二进制的 torn tail 一样丢掉，别的错还是报出来
+                let command = match command {
+                    Ok(command) => command,
+                    Err(e) if e.is_eof() => {
+                        log::warn!("dropping torn command at the end of {}: {}", path.display(), e);
+                        break;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                records.push(match command.action.as_str() {
+                    "set" => Record::set(command.key, command.value),
+                    "rm" => Record::remove(command.key),
+                    other => {
+                        return Err(KvsError::UnsupportedFormat(format!(
+                            "unknown legacy action {}",
+                            other
+                        )))
+                    }
+                });
+            }
+        }
+        FileFormat::Binary(version) => {
+            let mut file = File::open(path)?;
+            let len = file.metadata()?.len();
+            file.seek(SeekFrom::Start(FILE_HEADER_LEN))?;
+            let reader = BufReader::new(file);
+            for item in RecordReader::with_version(reader, path, *version, FILE_HEADER_LEN, len) {
+                let (_, _, record) = item?;
+                records.push(record);
+            }
+        }
+        FileFormat::Empty => {}
+    }
+    Ok(records)
+}
+
+/// Rewrite a file at `path` that is in an older `format` into the current one.
+///
+/// The new content goes to a temporary file that is renamed over the original,
+/// so a crash during the upgrade leaves the old file untouched.
+pub(crate) fn upgrade_file(path: &Path, format: &FileFormat) -> Result<()> {
+    let records = read_old_records(path, format)?;
+
+    let tmp_path = path.with_extension("upgrading");
+    let mut tmp = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    let mut out = Vec::new();
+    write_file_header(&mut out)?;
+    for record in records {
+        out.extend_from_slice(&record.encode());
+    }
+    tmp.write_all(&out)?;
+    tmp.sync_all()?;
+    drop(tmp);
+
+    fs::rename(&tmp_path, path)?;
+    log::info!("upgraded {} from {:?} to format version {}", path.display(), format, FORMAT_VERSION);
+    Ok(())
+}
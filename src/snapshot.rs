@@ -0,0 +1,107 @@
+//! Point-in-time snapshots.
+//!
+//! Every write to the log gets the next sequence number, and a snapshot reads the
+//! store as of the last write before it was taken. While snapshots are open the store
+//! keeps the log records that newer writes replace, or that compaction moves into an
+//! sstable, and a snapshot holds on to the sstables that existed when it was taken.
+//! Records are let go once no open snapshot can see them.
+
+use super::sstable::SsTable;
+use super::{Index, KvStore, Result};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::sync::Arc;
+
+/// A read-only view of a `KvStore` as of one moment, see `KvStore::snapshot`.
+///
+/// Writes made after the snapshot was taken are not visible through it, and keys
+/// with a TTL expire as of the moment it was taken. Keep snapshots short-lived: the
+/// store holds on to replaced data until they are dropped.
+#[derive(Debug)]
+pub struct Snapshot {
+    store: KvStore,
+    seq: u64,
+    now: u64,
+    tables: Vec<Arc<SsTable>>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(store: KvStore, seq: u64, now: u64, tables: Vec<Arc<SsTable>>) -> Snapshot {
+        Snapshot { store, seq, now, tables }
+    }
+
+    /// Sequence number of the last write the snapshot sees.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// The value of `key` as of the snapshot, `None` if it did not exist then.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let record = self.store.search_at(&key, self.seq, &self.tables)?;
+        Ok(record.filter(|record| record.is_live(self.now)).map(|record| record.value))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.store.retained.lock().unwrap().release(self.seq);
+    }
+}
+
+/// 打开着的快照，和为它们留下来的 log 记录
+#[derive(Debug, Default)]
+pub(crate) struct Retained {
+    snapshots: BTreeMap<u64, usize>, // 每个 seq 上打开着几个快照
+    history: HashMap<String, Vec<Version>>, // 每个 key 被替换掉或者压缩掉的记录，按 seq 从旧到新
+}
+
+/// 一条留下来的 log 记录；log 被压缩删掉以后也要能读，所以拿着文件句柄
+#[derive(Debug, Clone)]
+pub(crate) struct Version {
+    pub index: Index,
+    pub file: Arc<File>,
+}
+
+impl Retained {
+    /// 记下一个 seq 上新打开的快照
+    pub fn register(&mut self, seq: u64) {
+        *self.snapshots.entry(seq).or_default() += 1;
+    }
+
+    /// 关掉一个快照，剩下的快照都看不到的记录扔掉
+    fn release(&mut self, seq: u64) {
+        if let Entry::Occupied(mut entry) = self.snapshots.entry(seq) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+        match self.snapshots.keys().next() {
+            None => self.history.clear(),
+            Some(&oldest) => self.history.retain(|_, versions| {
+                // 最老的快照能看到的最新的那条之前的，哪个快照都用不上了
+                let needed = versions.iter().rposition(|version| version.index.seq <= oldest).unwrap_or(0);
+                versions.drain(..needed);
+                !versions.is_empty()
+            }),
+        }
+    }
+
+    /// 现在有没有打开着的快照，有的话被替换掉的记录要留下来
+    pub fn is_retaining(&self) -> bool {
+        !self.snapshots.is_empty()
+    }
+
+    /// 留下 key 的一条不再在 index 里的记录；没有打开着的快照的话什么都不做
+    pub fn retain(&mut self, key: &str, index: Index, file: Arc<File>) {
+        if self.is_retaining() {
+            self.history.entry(key.to_owned()).or_default().push(Version { index, file });
+        }
+    }
+
+    /// 留下来的 key 的记录里，seq 不超过 seq 的最新的那条
+    pub fn find(&self, key: &str, seq: u64) -> Option<Version> {
+        self.history.get(key)?.iter().rev().find(|version| version.index.seq <= seq).cloned()
+    }
+}
@@ -0,0 +1,342 @@
+//! Compacted data files, sorted by key and split into blocks.
+//!
+//! After the file header, an sstable holds its records in key order, packed into
+//! blocks of about `BLOCK_SIZE` bytes. A key may have several records, the versions
+//! kept by `VersionRetention`, newest first and never split across blocks. An index
+//! of the blocks, a Bloom filter of the keys and a footer follow:
+//!
+//! ```text
+//! | header | block | block | ... | index | filter | footer |
+//! index entry: | key length: u32 | first key of the block | block offset: u64 | block length: u64 |
+//! footer:      | index offset: u64 | index length: u64 | filter length: u64 | crc32: u32 | magic "KVSF" |
+//! ```
+//!
+//! The checksum covers the index and the filter. An empty filter means the table was
+//! written with filters turned off. Tables from before filters end in a shorter footer
+//! without the filter length and with the magic `KVST`; they are read without a filter.
+//!
+//! Only the index and the filter are kept in memory, so a lookup asks the filter,
+//! then binary-searches the index and reads one block.
+//! Sstables written by older releases are plain unsorted record streams; `SsTable::open`
+//! rewrites them.
+
+use super::bloom::{self, BloomFilter};
+use super::record::{self, Record, RecordReader, FILE_HEADER_LEN};
+use super::{open_data_file, open_data_file_read_only, sync_dir, KvStoreOptions, KvsError, Result};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Blocks are closed once adding the next record would take them past this size.
+const BLOCK_SIZE: u64 = 4096;
+const FOOTER_MAGIC: &[u8; 4] = b"KVSF";
+const FOOTER_LEN: u64 = 32;
+/// Footer of the tables written before Bloom filters.
+const UNFILTERED_FOOTER_MAGIC: &[u8; 4] = b"KVST";
+const UNFILTERED_FOOTER_LEN: u64 = 24;
+
+/// An open sstable file together with its block index and Bloom filter.
+///
+/// Sstables are named `sstable_<gen>.txt`. A larger generation holds newer data.
+/// The file stays open for reading, so a table that is still in use can be read
+/// after compaction has deleted it.
+#[derive(Debug)]
+pub(crate) struct SsTable {
+    pub gen: u64,
+    path: PathBuf,
+    file: File,
+    blocks: Vec<Block>,
+    filter: Option<BloomFilter>,
+}
+
+/// Where a block is, and the first key in it.
+#[derive(Debug)]
+struct Block {
+    first_key: String,
+    offset: u64,
+    len: u64,
+}
+
+/// Name of the sstable file of generation `gen`.
+pub(crate) fn file_name(gen: u64) -> String {
+    format!("sstable_{}.txt", gen)
+}
+
+impl SsTable {
+    /// Open an existing sstable and load its block index and filter.
+    ///
+    /// An sstable in the old unsorted layout is rewritten first, which a read-only
+    /// open refuses to do.
+    pub fn open(dir_path: &Path, gen: u64, options: &KvStoreOptions) -> Result<SsTable> {
+        let path = dir_path.join(file_name(gen));
+        let read_only = options.read_only;
+        let mut file = if read_only {
+            open_data_file_read_only(&path)?
+        } else {
+            open_data_file(&path)?
+        };
+
+        if let Some((blocks, filter)) = read_meta(&file, &path)? {
+            return Ok(SsTable { gen, path, file, blocks, filter });
+        }
+        if read_only {
+            return Err(KvsError::UnsupportedFormat(format!(
+                "{} must be upgraded by opening the store writable",
+                path.display()
+            )));
+        }
+
+        // 老格式就是一串没排序的记录，排好序重写一遍；尾巴上写坏的记录直接丢掉
+        let len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(FILE_HEADER_LEN))?;
+        let mut records = BTreeMap::new();
+        let mut reader = RecordReader::new(BufReader::new(&file), &path, FILE_HEADER_LEN, len);
+        for item in &mut reader {
+            let (_, _, record) = item?;
+            records.insert(record.key.clone(), record);
+        }
+        if let Some(torn_at) = reader.torn_at() {
+            log::warn!("dropping torn record at offset {} of {}", torn_at, path.display());
+        }
+        drop(file);
+        SsTable::create(dir_path, gen, options.bloom_bits_per_key, records.into_values().map(Ok))
+    }
+
+    /// Write `records`, which must be sorted by key, into a new sstable of generation `gen`,
+    /// with a filter of `bits_per_key` bits per key, or none if it is 0.
+    ///
+    /// The file is written under a temporary name and renamed once it is durable,
+    /// so it never becomes visible half written.
+    pub fn create(
+        dir_path: &Path,
+        gen: u64,
+        bits_per_key: u32,
+        records: impl IntoIterator<Item = Result<Record>>,
+    ) -> Result<SsTable> {
+        let path = dir_path.join(file_name(gen));
+        let tmp_path = path.with_extension("tmp");
+        let _ = fs::remove_file(&tmp_path);
+        let file = open_data_file(&tmp_path)?;
+        let mut writer = BufWriter::new(&file);
+
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut hashes = Vec::new();
+        let mut offset = FILE_HEADER_LEN;
+        let mut last_key: Option<String> = None;
+        for record in records {
+            let record = record?;
+            let buffer = record.encode();
+            // 同一个 key 的几个版本放在一个块里，查的时候只用读一个块
+            let same_key = last_key.as_ref() == Some(&record.key);
+            if bits_per_key > 0 && !same_key {
+                hashes.push(bloom::hash(&record.key));
+            }
+            match blocks.last_mut() {
+                Some(block) if same_key || block.len + buffer.len() as u64 <= BLOCK_SIZE => {
+                    block.len += buffer.len() as u64;
+                }
+                _ => blocks.push(Block {
+                    first_key: record.key.clone(),
+                    offset,
+                    len: buffer.len() as u64,
+                }),
+            }
+            last_key = Some(record.key);
+            writer.write_all(&buffer)?;
+            offset += buffer.len() as u64;
+        }
+
+        let mut index = Vec::new();
+        for block in &blocks {
+            index.extend_from_slice(&(block.first_key.len() as u32).to_le_bytes());
+            index.extend_from_slice(block.first_key.as_bytes());
+            index.extend_from_slice(&block.offset.to_le_bytes());
+            index.extend_from_slice(&block.len.to_le_bytes());
+        }
+        // 空表也照样写一个过滤器，长度为 0 只用来表示关掉了过滤器
+        let filter = (bits_per_key > 0).then(|| BloomFilter::build(&hashes, bits_per_key));
+        let encoded_filter = filter.as_ref().map_or_else(Vec::new, BloomFilter::encode);
+        let mut checksum = crc32fast::Hasher::new();
+        checksum.update(&index);
+        checksum.update(&encoded_filter);
+
+        writer.write_all(&index)?;
+        writer.write_all(&encoded_filter)?;
+        writer.write_all(&offset.to_le_bytes())?;
+        writer.write_all(&(index.len() as u64).to_le_bytes())?;
+        writer.write_all(&(encoded_filter.len() as u64).to_le_bytes())?;
+        writer.write_all(&checksum.finalize().to_le_bytes())?;
+        writer.write_all(FOOTER_MAGIC)?;
+        writer.flush()?;
+        drop(writer);
+
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        sync_dir(dir_path)?;
+
+        Ok(SsTable { gen, path, file, blocks, filter })
+    }
+
+    /// Ask the filter whether the key with this `bloom::hash` may be in the table.
+    /// `None` if the table has no filter.
+    pub fn may_contain(&self, hash: u64) -> Option<bool> {
+        self.filter.as_ref().map(|filter| filter.may_contain(hash))
+    }
+
+    /// The newest record for `key` in this file, if there is one. Reads at most one block.
+    pub fn get(&self, key: &str) -> Result<Option<Record>> {
+        Ok(self.versions(key)?.into_iter().next())
+    }
+
+    /// All the records for `key` in this file, newest first. Reads at most one block.
+    pub fn versions(&self, key: &str) -> Result<Vec<Record>> {
+        let block = match self.blocks.partition_point(|block| block.first_key.as_str() <= key) {
+            0 => return Ok(Vec::new()),
+            i => i - 1,
+        };
+        let block = &self.blocks[block];
+        let buffer = self.read_bytes(block)?;
+        record::find_sorted(&buffer, &self.path, block.offset, key)
+    }
+
+    /// The records with keys in `range`, in key order. Blocks are read as the
+    /// iterator reaches them.
+    pub fn scan(
+        self: &Arc<Self>,
+        range: (Bound<String>, Bound<String>),
+    ) -> impl Iterator<Item = Result<Record>> + Send {
+        // 从可能含有起点的那个块开始读
+        let first_block = match &range.0 {
+            Bound::Included(start) | Bound::Excluded(start) => self
+                .blocks
+                .partition_point(|block| block.first_key <= *start)
+                .saturating_sub(1),
+            Bound::Unbounded => 0,
+        };
+        let table = self.clone();
+        let (start, end) = range;
+        (first_block..table.blocks.len())
+            .map(move |block| table.read_block(block))
+            .flat_map(|block| match block {
+                Ok(records) => records.into_iter().map(Ok).collect::<Vec<_>>(),
+                Err(e) => vec![Err(e)],
+            })
+            .skip_while(move |record| match (record, &start) {
+                (Ok(record), Bound::Included(start)) => record.key < *start,
+                (Ok(record), Bound::Excluded(start)) => record.key <= *start,
+                _ => false,
+            })
+            .take_while(move |record| match (record, &end) {
+                (Ok(record), Bound::Included(end)) => record.key <= *end,
+                (Ok(record), Bound::Excluded(end)) => record.key < *end,
+                _ => true,
+            })
+    }
+
+    fn read_bytes(&self, block: &Block) -> Result<Vec<u8>> {
+        let mut buffer = vec![0u8; block.len as usize];
+        record::read_exact_at(&self.file, &mut buffer, block.offset)?;
+        Ok(buffer)
+    }
+
+    /// Read and decode every record of block `block`.
+    fn read_block(&self, block: usize) -> Result<Vec<Record>> {
+        let block = &self.blocks[block];
+        let buffer = self.read_bytes(block)?;
+
+        let end = block.offset + block.len;
+        let mut reader = RecordReader::new(&buffer[..], &self.path, block.offset, end);
+        let records = (&mut reader)
+            .map(|item| item.map(|(_, _, record)| record))
+            .collect::<Result<Vec<_>>>()?;
+        // 块是整个写进去的，中间断掉只能是坏了
+        match reader.torn_at() {
+            Some(offset) => Err(KvsError::Corruption {
+                file: self.path.display().to_string(),
+                offset,
+            }),
+            None => Ok(records),
+        }
+    }
+
+    /// Delete the file once it has been merged into a newer one. Readers still holding
+    /// this table keep reading through the open handle.
+    pub fn remove_file(&self) -> Result<()> {
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Load the block index and the filter through the footer. `None` means the file has
+/// no footer, i.e. it is in the old unsorted layout.
+fn read_meta(file: &File, path: &Path) -> Result<Option<(Vec<Block>, Option<BloomFilter>)>> {
+    let len = file.metadata()?.len();
+    if len < FILE_HEADER_LEN + UNFILTERED_FOOTER_LEN {
+        return Ok(None);
+    }
+    let mut magic = [0u8; 4];
+    record::read_exact_at(file, &mut magic, len - 4)?;
+    let footer_len = match &magic {
+        FOOTER_MAGIC if len >= FILE_HEADER_LEN + FOOTER_LEN => FOOTER_LEN,
+        UNFILTERED_FOOTER_MAGIC => UNFILTERED_FOOTER_LEN,
+        _ => return Ok(None),
+    };
+    let mut footer = vec![0u8; footer_len as usize];
+    record::read_exact_at(file, &mut footer, len - footer_len)?;
+
+    let corruption = || KvsError::Corruption {
+        file: path.display().to_string(),
+        offset: len - footer_len,
+    };
+    let u64_at = |buf: &[u8], at: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&buf[at..at + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    let index_offset = u64_at(&footer, 0);
+    let index_len = u64_at(&footer, 8);
+    let filter_len = if footer_len == FOOTER_LEN { u64_at(&footer, 16) } else { 0 };
+    let checksum_at = footer.len() - 8;
+    let checksum = u32::from_le_bytes([
+        footer[checksum_at],
+        footer[checksum_at + 1],
+        footer[checksum_at + 2],
+        footer[checksum_at + 3],
+    ]);
+    let meta_end = index_offset.checked_add(index_len).and_then(|end| end.checked_add(filter_len));
+    if meta_end != Some(len - footer_len) || index_offset < FILE_HEADER_LEN {
+        return Err(corruption());
+    }
+
+    let mut meta = vec![0u8; (index_len + filter_len) as usize];
+    record::read_exact_at(file, &mut meta, index_offset)?;
+    if crc32fast::hash(&meta) != checksum {
+        return Err(corruption());
+    }
+    let (index, filter) = meta.split_at(index_len as usize);
+    let filter = match filter {
+        [] => None,
+        filter => Some(BloomFilter::decode(filter).ok_or_else(corruption)?),
+    };
+
+    let mut blocks = Vec::new();
+    let mut rest = index;
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(corruption());
+        }
+        let key_len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        if rest.len() < 4 + key_len + 16 {
+            return Err(corruption());
+        }
+        let first_key = String::from_utf8(rest[4..4 + key_len].to_vec())?;
+        let offset = u64_at(rest, 4 + key_len);
+        let len = u64_at(rest, 12 + key_len);
+        blocks.push(Block { first_key, offset, len });
+        rest = &rest[4 + key_len + 16..];
+    }
+    Ok(Some((blocks, filter)))
+}
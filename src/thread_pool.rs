@@ -3,7 +3,6 @@
 
 use super::{Result, KvsError};
 use std::thread;
-use std::sync::{Arc, mpsc, Mutex};
 use crossbeam::channel::{self, Receiver, Sender};
 
 /// the basic ThreadPool
@@ -22,7 +21,7 @@ pub trait ThreadPool {
 pub struct NaiveThreadPool;
 
 impl ThreadPool for NaiveThreadPool {
-    fn new(threads: u32) -> Result<Self> {
+    fn new(_threads: u32) -> Result<Self> {
         Ok(NaiveThreadPool)
     }
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
@@ -0,0 +1,50 @@
+//! Optimistic transactions over several keys.
+
+use super::{Result, Snapshot};
+use std::collections::{BTreeMap, HashMap};
+
+/// The reads and writes of one attempt at a transaction, see `KvStore::transaction`.
+///
+/// Reads see the store as of the moment the attempt started, together with the
+/// attempt's own writes. Writes are buffered and applied atomically on commit, and
+/// only if none of the keys read has been written to since.
+#[derive(Debug)]
+pub struct Transaction {
+    pub(crate) snapshot: Snapshot,
+    pub(crate) reads: HashMap<String, Option<String>>, // 读过的 key 和当时读到的值，提交的时候要核对
+    pub(crate) writes: BTreeMap<String, Option<String>>, // 要写的值，None 是删除
+}
+
+impl Transaction {
+    pub(crate) fn new(snapshot: Snapshot) -> Transaction {
+        Transaction {
+            snapshot,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// The value of `key`: what this transaction wrote to it, or else its value when
+    /// the transaction started.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.reads.get(&key) {
+            return Ok(value.clone());
+        }
+        let value = self.snapshot.get(key.clone())?;
+        self.reads.insert(key, value.clone());
+        Ok(value)
+    }
+
+    /// Set `key` to `value` when the transaction commits.
+    pub fn set(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Remove `key` when the transaction commits. A key that does not exist is not an error.
+    pub fn remove(&mut self, key: String) {
+        self.writes.insert(key, None);
+    }
+}
@@ -0,0 +1,51 @@
+//! Writes to several keys that are applied together.
+
+use super::record::Record;
+
+/// Sets and removes that `KvsEngine::write_batch` applies atomically: after a crash
+/// either all of them are there or none is.
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine, WriteBatch};
+/// # let store = KvStore::open("data")?;
+/// let mut batch = WriteBatch::new();
+/// batch
+///     .set("user/1".to_owned(), "alice@example.com".to_owned())
+///     .remove("email/alice@old.example.com".to_owned())
+///     .set("email/alice@example.com".to_owned(), "user/1".to_owned());
+/// store.write_batch(batch)?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    pub(crate) records: Vec<Record>,
+}
+
+impl WriteBatch {
+    /// An empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Set `key` to `value`. Later writes to the same key in the batch win.
+    pub fn set(&mut self, key: String, value: String) -> &mut WriteBatch {
+        self.records.push(Record::set(key, value));
+        self
+    }
+
+    /// Remove `key`. Unlike `KvsEngine::remove`, a key that does not exist is not an error.
+    pub fn remove(&mut self, key: String) -> &mut WriteBatch {
+        self.records.push(Record::remove(key));
+        self
+    }
+
+    /// Number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Whether the batch has no writes.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
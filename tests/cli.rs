@@ -1,6 +1,7 @@
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::path::Path;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -20,28 +21,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +53,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +92,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +124,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +135,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,25 +146,62 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kvs-server --sync` should reject an unknown policy
+#[test]
+fn server_cli_invalid_sync() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--sync", "sometimes", "--addr", "127.0.0.1:4003"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// A second server on the same data directory refuses to start
+#[test]
+fn server_cli_dir_in_use() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("in use"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for the server");
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for the server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -176,19 +214,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait for the server"); // release the directory lock
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -196,7 +235,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -204,7 +243,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -212,7 +251,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -220,7 +259,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -228,7 +267,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -236,7 +275,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -244,7 +283,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -257,26 +296,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait for the server"); // release the directory lock
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -294,3 +334,342 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `kvs-client scan` lists pairs in key order, by range or by prefix
+#[test]
+fn cli_scan() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for (key, value) in &[("user/2", "bob"), ("user/1", "alice"), ("team/1", "red"), ("user/3", "carol")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, value, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--prefix", "user/", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user/1 alice\nuser/2 bob\nuser/3 carol\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--from", "team/", "--to", "user/3", "--limit", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("team/1 red\nuser/1 alice\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--prefix", "user/", "--from", "a", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for the server");
+}
+
+// `kvs-client batch` applies all its writes, or none if one of them is malformed
+#[test]
+fn cli_batch() {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "email/old", "user1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["batch", "set", "user1", "new", "rm", "email/old", "set", "email/new", "user1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["batch", "set", "user1", "newer", "set", "dangling", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Batch Failed"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("email/new user1\nuser1 new\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for the server");
+}
+
+// Conditional writes exit with code 3 when the value is not the expected one
+#[test]
+fn cli_conditional_writes() {
+    let addr = "127.0.0.1:4010";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+
+    client(&["set-if-absent", "key1", "value1"]).assert().success().stdout(is_empty());
+    client(&["set-if-absent", "key1", "value2"]).assert().code(3).stderr(contains("Conflict"));
+    client(&["cas", "key1", "--expected", "wrong", "--new", "value2"]).assert().code(3);
+    client(&["cas", "key1", "--expected", "value1", "--new", ""]).assert().success();
+    client(&["get", "key1"]).assert().success().stdout("\n");
+    client(&["rm-if-equals", "key1", "value1"]).assert().code(3);
+    client(&["rm-if-equals", "key1", ""]).assert().success();
+    client(&["get", "key1"]).assert().success().stdout(contains("Key not found"));
+    client(&["cas", "key1", "--new", "value3"]).assert().success();
+    client(&["get", "key1"]).assert().success().stdout("value3\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for the server");
+}
+
+// `kvs-client incr` and `decr` print the new value
+#[test]
+fn cli_incr_decr() {
+    let addr = "127.0.0.1:4011";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+
+    client(&["incr", "counter"]).assert().success().stdout("1\n");
+    client(&["incr", "counter", "--by", "10"]).assert().success().stdout("11\n");
+    client(&["decr", "counter", "--by", "-4"]).assert().success().stdout("15\n");
+    client(&["decr", "counter", "--by", "20"]).assert().success().stdout("-5\n");
+    client(&["set", "name", "alice"]).assert().success();
+    client(&["incr", "name"]).assert().failure().stderr(contains("not an integer"));
+    client(&["incr", "counter", "--by", "x"]).assert().failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for the server");
+}
+
+// `kvs-client set --ttl` sets a key that expires, `kvs-client ttl` prints the seconds left
+#[test]
+fn cli_set_with_ttl() {
+    let addr = "127.0.0.1:4012";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+
+    client(&["set", "session", "token", "--ttl", "1"]).assert().success().stdout(is_empty());
+    client(&["set", "long", "token", "--ttl", "3600"]).assert().success();
+    client(&["set", "plain", "value"]).assert().success();
+    client(&["get", "session"]).assert().success().stdout("token\n");
+    client(&["ttl", "session"]).assert().success().stdout("1\n");
+    client(&["ttl", "long"]).assert().success().stdout("3600\n");
+    client(&["ttl", "plain"]).assert().success().stdout("No expiry\n");
+    client(&["ttl", "missing"]).assert().success().stdout("Key not found\n");
+    client(&["set", "bad", "value", "--ttl", "soon"]).assert().failure();
+    // 值里带空格不能被截断，也不能把后半截当成 TTL
+    client(&["set", "spaced", "hello 60000"]).assert().failure().stderr(contains("Set Failed"));
+    client(&["set", "spaced", "hello 60000", "--ttl", "10"]).assert().failure();
+    client(&["get", "spaced"]).assert().success().stdout("Key not found\n");
+    client(&["ttl", "spaced"]).assert().success().stdout("Key not found\n");
+
+    thread::sleep(Duration::from_millis(1100));
+    client(&["get", "session"]).assert().success().stdout("Key not found\n");
+    client(&["ttl", "session"]).assert().success().stdout("Key not found\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for the server");
+}
+
+// `kvs-client history` lists the versions a server started with `--retain` keeps
+#[test]
+fn cli_history() {
+    let addr = "127.0.0.1:4013";
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--retain", "versions:0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--retain", "versions:2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+
+    client(&["set", "config", "red"]).assert().success();
+    client(&["set", "config", "green"]).assert().success();
+    client(&["rm", "config"]).assert().success();
+    client(&["set", "config", "blue"]).assert().success();
+    let output = client(&["history", "config"]).output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<Vec<&str>> = stdout.lines().map(|line| line.split(' ').collect()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!((lines[0][0], lines[0][2], lines[0][3]), ("4", "set", "blue"));
+    assert_eq!((lines[1][0], &lines[1][2..]), ("3", &["rm"][..]));
+    assert!(lines[0][1].parse::<u64>().is_ok());
+    client(&["history", "missing"]).assert().success().stdout(is_empty());
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for the server");
+}
+
+#[test]
+fn cli_backup() {
+    let addr = "127.0.0.1:4014";
+    let temp_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let other_root = TempDir::new().unwrap();
+    let root = backup_dir.path().to_str().unwrap();
+
+    // 不是 store 的目录不备份，也不在里面留下文件
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--backup-to", "first", "--backup-root", root])
+        .current_dir(&backup_dir)
+        .assert()
+        .failure()
+        .stdout(contains("no store"));
+    assert_eq!(fs::read_dir(&backup_dir).unwrap().count(), 0);
+    // 备份目录总是在 --backup-root 下面
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--backup-to", "first"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--backup-root"));
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--backup-root", root])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["set", "key2", "value2"]).assert().success();
+    client(&["rm", "key2"]).assert().success();
+
+    let backup_into = |root: &str, dest: &str| {
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(["--addr", addr, "--backup-to", dest, "--backup-root", root]).current_dir(&temp_dir);
+        cmd
+    };
+    let backup = |dest: &str| backup_into(root, dest);
+    let outside = temp_dir.path().join("outside");
+    let rejected = ["../outside", outside.to_str().unwrap(), ""];
+
+    // 目录被 server 占着，通过 server 备份，server 只认它自己的 backup root
+    backup("first").assert().success();
+    backup("first").assert().failure().stdout(contains("not empty"));
+    for dest in rejected {
+        backup(dest).assert().failure().stdout(contains("not inside the backup root"));
+    }
+    backup_into(other_root.path().to_str().unwrap(), "elsewhere")
+        .assert()
+        .failure()
+        .stdout(contains("not inside the backup root"));
+    assert_eq!(fs::read_dir(&other_root).unwrap().count(), 0);
+    client(&["set", "key3", "value3"]).assert().success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // server 停了就自己只读打开备份，规则和上面一样
+    for dest in rejected {
+        backup(dest).assert().failure().stdout(contains("not inside the backup root"));
+    }
+    assert!(!outside.exists());
+    backup("second").assert().success();
+
+    let get = |dir: &Path, key: &str| {
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr])
+            .current_dir(dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        let output = Command::cargo_bin("kvs-client").unwrap().args(["get", key, "--addr", addr]).output().unwrap();
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let first = backup_dir.path().join("first");
+    assert_eq!(get(&first, "key1").trim(), "value1");
+    assert_eq!(get(&first, "key2").trim(), "Key not found");
+    assert_eq!(get(&first, "key3").trim(), "Key not found");
+    assert_eq!(get(&backup_dir.path().join("second"), "key3").trim(), "value3");
+}
@@ -0,0 +1,321 @@
+use kvs::{
+    CompactionTrigger, FileLayer, KvStore, KvStoreOptions, KvsEngine, KvsError, LogFile, Result,
+    SyncPolicy,
+};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Remembers how much of each log has been synced, so a power failure can be simulated
+// by cutting the files back to that length.
+#[derive(Debug, Default)]
+struct FaultyLayer {
+    synced: Arc<Mutex<HashMap<PathBuf, u64>>>,
+    fail_sync: Arc<AtomicBool>,
+    // A slow disk, so that concurrent writers pile up behind a sync
+    sync_delay: Duration,
+    syncs: Arc<AtomicUsize>,
+}
+
+#[derive(Debug)]
+struct FaultyFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    synced: Arc<Mutex<HashMap<PathBuf, u64>>>,
+    fail_sync: Arc<AtomicBool>,
+    sync_delay: Duration,
+    syncs: Arc<AtomicUsize>,
+}
+
+impl FileLayer for FaultyLayer {
+    fn open_log(&self, path: &Path, file: File) -> io::Result<Box<dyn LogFile>> {
+        let written = file.metadata()?.len();
+        self.synced.lock().unwrap().insert(path.to_owned(), written);
+        Ok(Box::new(FaultyFile {
+            path: path.to_owned(),
+            file,
+            written,
+            synced: self.synced.clone(),
+            fail_sync: self.fail_sync.clone(),
+            sync_delay: self.sync_delay,
+            syncs: self.syncs.clone(),
+        }))
+    }
+}
+
+impl Write for FaultyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl LogFile for FaultyFile {
+    fn sync_data(&mut self) -> io::Result<()> {
+        if self.fail_sync.load(Ordering::SeqCst) {
+            return Err(io::Error::other("injected sync failure"));
+        }
+        thread::sleep(self.sync_delay);
+        self.file.sync_data()?;
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        self.synced.lock().unwrap().insert(self.path.clone(), self.written);
+        Ok(())
+    }
+}
+
+impl FaultyLayer {
+    // What would survive a power failure right now
+    fn durable(&self) -> HashMap<PathBuf, u64> {
+        self.synced.lock().unwrap().clone()
+    }
+}
+
+fn power_failure(durable: HashMap<PathBuf, u64>) -> Result<()> {
+    for (path, len) in durable {
+        OpenOptions::new().write(true).open(path)?.set_len(len)?;
+    }
+    Ok(())
+}
+
+// Write `count` keys under `sync`, lose everything that was not synced and reopen
+fn write_and_crash(temp_dir: &TempDir, sync: SyncPolicy, count: usize) -> Result<KvStore> {
+    let layer = Arc::new(FaultyLayer::default());
+    let options = KvStoreOptions::new().sync(sync).file_layer(layer.clone());
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..count {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    if let SyncPolicy::Interval(interval) = sync {
+        thread::sleep(interval * 10);
+    }
+
+    let durable = layer.durable();
+    drop(store);
+    power_failure(durable)?;
+    KvStore::open(temp_dir.path())
+}
+
+#[test]
+fn sync_never_can_lose_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = write_and_crash(&temp_dir, SyncPolicy::Never, 5)?;
+    for i in 0..5 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    Ok(())
+}
+
+#[test]
+fn sync_always_keeps_every_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = write_and_crash(&temp_dir, SyncPolicy::Always, 5)?;
+    for i in 0..5 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+#[test]
+fn sync_every_n_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = write_and_crash(&temp_dir, SyncPolicy::EveryN(3), 7)?;
+    for i in 0..6 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("key6".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn sync_on_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = write_and_crash(&temp_dir, SyncPolicy::Interval(Duration::from_millis(10)), 5)?;
+    for i in 0..5 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// A log is synced before it is sealed, whatever the policy's count says
+#[test]
+fn sync_sealed_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let layer = Arc::new(FaultyLayer::default());
+    let options = KvStoreOptions::new()
+        .sync(SyncPolicy::EveryN(1000))
+        .compaction(CompactionTrigger::Disabled)
+        .max_log_size(256)
+        .file_layer(layer.clone());
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let durable = layer.durable();
+    drop(store);
+    power_failure(durable)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// A failed sync fails the write
+#[test]
+fn sync_failure_is_reported() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let layer = Arc::new(FaultyLayer {
+        fail_sync: Arc::new(AtomicBool::new(true)),
+        ..FaultyLayer::default()
+    });
+    let options = KvStoreOptions::new().sync(SyncPolicy::Always).file_layer(layer.clone());
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert!(matches!(
+        store.set("key1".to_owned(), "value1".to_owned()),
+        Err(KvsError::IoError(_))
+    ));
+
+    // The failed write is gone, and later writes land where the index says they are
+    layer.fail_sync.store(false, Ordering::SeqCst);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A write that made it into the log is acknowledged even if sealing the log after it fails
+#[test]
+fn seal_failure_keeps_the_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let layer = Arc::new(FaultyLayer {
+        fail_sync: Arc::new(AtomicBool::new(true)),
+        ..FaultyLayer::default()
+    });
+    let options = KvStoreOptions::new()
+        .sync(SyncPolicy::EveryN(1000))
+        .compaction(CompactionTrigger::Disabled)
+        .max_log_size(256)
+        .file_layer(layer.clone());
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    layer.fail_sync.store(false, Ordering::SeqCst);
+    for i in 20..40 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..40 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..40 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Concurrent writers share syncs, and every acknowledged write survives a crash
+#[test]
+fn group_commit_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let layer = Arc::new(FaultyLayer {
+        sync_delay: Duration::from_millis(2),
+        ..FaultyLayer::default()
+    });
+    let options = KvStoreOptions::new()
+        .sync(SyncPolicy::Always)
+        .file_layer(layer.clone());
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    let barrier = Arc::new(Barrier::new(16));
+    let handles: Vec<_> = (0..16)
+        .map(|thread_id| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..20 {
+                    store
+                        .set(format!("key{}_{}", thread_id, i), format!("value{}", i))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert!(layer.syncs.load(Ordering::SeqCst) < 16 * 20);
+
+    let durable = layer.durable();
+    drop(store);
+    power_failure(durable)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..16 {
+        for i in 0..20 {
+            assert_eq!(
+                store.get(format!("key{}_{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Every writer in a failed batch gets the error
+#[test]
+fn group_commit_failure_reaches_followers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let layer = Arc::new(FaultyLayer {
+        fail_sync: Arc::new(AtomicBool::new(true)),
+        ..FaultyLayer::default()
+    });
+    let options = KvStoreOptions::new().sync(SyncPolicy::Always).file_layer(layer);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || store.set(format!("key{}", thread_id), "value".to_owned()))
+        })
+        .collect();
+    for handle in handles {
+        assert!(matches!(handle.join().unwrap(), Err(KvsError::IoError(_))));
+    }
+    Ok(())
+}
+
+#[test]
+fn parse_sync_policy() {
+    assert_eq!("never".parse::<SyncPolicy>().unwrap(), SyncPolicy::Never);
+    assert_eq!("always".parse::<SyncPolicy>().unwrap(), SyncPolicy::Always);
+    assert_eq!("every:3".parse::<SyncPolicy>().unwrap(), SyncPolicy::EveryN(3));
+    assert_eq!(
+        "interval:50".parse::<SyncPolicy>().unwrap(),
+        SyncPolicy::Interval(Duration::from_millis(50))
+    );
+    assert!("every:0".parse::<SyncPolicy>().is_err());
+    assert!("sometimes".parse::<SyncPolicy>().is_err());
+}
@@ -1,6 +1,12 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    BloomStats, CompactionTrigger, KvStore, KvStoreOptions, KvsEngine, KvsError, Result, SyncPolicy, VersionRetention,
+    WriteBatch,
+};
+use std::path::Path;
+use std::fs;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -63,68 +69,1457 @@ fn get_non_existent_value() -> Result<()> {
     Ok(())
 }
 
+// Values are stored verbatim, whatever bytes they contain
+#[test]
+fn store_arbitrary_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let value = "{\"action\":\"rm\"} \n\t\0 値".to_owned();
+    store.set("key1".to_owned(), value.clone())?;
+    store.set("key2".to_owned(), String::new())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key2".to_owned())?, Some(String::new()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+    assert_eq!(store.get("key2".to_owned())?, Some(String::new()));
+
+    Ok(())
+}
+
+// Data directories written in the old JSON format should still open
+#[test]
+fn open_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("sstable_0.txt"),
+        r#"{"action":"set","key":"key1","value":"old"}{"action":"set","key":"key2","value":"value2"}"#,
+    )?;
+    fs::write(
+        temp_dir.path().join("log.txt"),
+        r#"{"action":"set","key":"key1","value":"value1"}{"action":"set","key":"key3","value":"value3"}{"action":"rm","key":"key3","value":""}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+
+    // Open from disk again, the files are in the new format by now
+    drop(store);
+    assert!(!temp_dir.path().join("log.txt").exists());
+    assert!(fs::read(temp_dir.path().join("log_0.txt"))?.starts_with(b"KVSB"));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// A version 2 record: no sequence number, write time or expiry
+fn v2_record(record_type: u8, key: &str, value: &str) -> Vec<u8> {
+    let mut body = vec![record_type];
+    body.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    body.extend_from_slice(key.as_bytes());
+    body.extend_from_slice(value.as_bytes());
+    let mut record = crc32fast::hash(&body).to_le_bytes().to_vec();
+    record.extend_from_slice(&body);
+    record
+}
+
+// Version 2 files are read as they are, and get the current version once they are written to
+#[test]
+fn open_version_2_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("log_0.txt");
+    let mut content = b"KVSB".to_vec();
+    content.extend_from_slice(&2u32.to_le_bytes());
+    content.extend_from_slice(&v2_record(1, "key1", "value1"));
+    content.extend_from_slice(&v2_record(1, "key2", "value2"));
+    content.extend_from_slice(&v2_record(2, "key1", ""));
+    fs::write(&log_path, &content)?;
+
+    // 只读打开不改文件
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    assert_eq!(fs::read(&log_path)?, content);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set_with_ttl("key3".to_owned(), "value3".to_owned(), Duration::from_secs(3600))?;
+    assert_eq!(store.history("key2".to_owned())?[0].seq, 0);
+    drop(store);
+    let upgraded = fs::read(&log_path)?;
+    assert_eq!(&upgraded[4..8], &3u32.to_le_bytes());
+    assert_eq!(&upgraded[8..content.len()], &content[8..]);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert!(store.ttl("key3".to_owned())?.is_some());
+    drop(store);
+
+    // 比自己新的版本不认
+    let mut newer = upgraded;
+    newer[4..8].copy_from_slice(&4u32.to_le_bytes());
+    fs::write(&log_path, newer)?;
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::UnsupportedFormat(_))));
+
+    Ok(())
+}
+
+// A JSON command cut short by an unclean shutdown is dropped by the upgrade
+#[test]
+fn open_legacy_json_log_with_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("log.txt"),
+        r#"{"action":"set","key":"key1","value":"value1"}{"action":"set","key":"key2","val"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // 中间坏掉的 JSON 不是 torn tail，还是报错
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("log.txt"),
+        r#"{"action":"set","key":"key1",,}{"action":"set","key":"key2","value":"value2"}"#,
+    )?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    Ok(())
+}
+
+// A record cut short by an unclean shutdown is dropped on open
+#[test]
+fn truncate_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("log_0.txt");
+    let len = fs::metadata(&log_path)?.len();
+    fs::OpenOptions::new().write(true).open(&log_path)?.set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A damaged record in the middle of the log is reported, not skipped
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Flip a byte in the key of the first record, right after the file header
+    let log_path = temp_dir.path().join("log_0.txt");
+    let mut content = fs::read(&log_path)?;
+    content[8 + 13] ^= 0xff;
+    fs::write(&log_path, content)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { file, offset }) => {
+            assert!(file.ends_with("log_0.txt"));
+            assert_eq!(offset, 8);
+        }
+        other => panic!("expected corruption, got {:?}", other.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// A damaged length field is not mistaken for a torn tail, which would drop the records after it
+#[test]
+fn detect_damaged_record_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned()).set("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch)?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    // 第一条记录的 value 长度改得超出文件末尾
+    let log_path = temp_dir.path().join("log_0.txt");
+    let content = fs::read(&log_path)?;
+    let mut damaged = content.clone();
+    damaged[8 + 9..8 + 13].copy_from_slice(&u32::MAX.to_le_bytes());
+    fs::write(&log_path, &damaged)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { offset, .. }) => assert_eq!(offset, 8),
+        other => panic!("expected corruption, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(fs::metadata(&log_path)?.len(), content.len() as u64);
+
+    // batch 的长度坏了也一样，后面那条不是 batch 里的
+    let field = |at: usize| u32::from_le_bytes(content[at..at + 4].try_into().unwrap()) as usize;
+    let frame = 8 + 13 + field(8 + 5) + field(8 + 9);
+    let frame_end = frame + 13 + field(frame + 9);
+    let mut damaged = content.clone();
+    damaged[frame + 9..frame + 13].copy_from_slice(&u32::MAX.to_le_bytes());
+    fs::write(&log_path, &damaged)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { offset, .. }) => assert_eq!(offset, frame as u64),
+        other => panic!("expected corruption, got {:?}", other.map(|_| ())),
+    }
+
+    // 真写到一半的 batch，里面写完了的记录也不算，还是当成 torn tail 截掉
+    fs::write(&log_path, &content[..frame_end - 3])?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(fs::metadata(&log_path)?.len(), frame as u64);
+
+    Ok(())
+}
+
+// A batch applies all its writes in order, and they survive reopening and compaction
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionTrigger::Disabled);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("user/1".to_owned(), "alice@old".to_owned())?;
+    store.set("email/alice@old".to_owned(), "user/1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("user/1".to_owned(), "alice@new".to_owned())
+        .remove("email/alice@old".to_owned())
+        .set("email/alice@new".to_owned(), "user/0".to_owned())
+        .set("email/alice@new".to_owned(), "user/1".to_owned())
+        .remove("never/set".to_owned());
+    assert_eq!(batch.len(), 5);
+    store.write_batch(batch)?;
+    store.write_batch(WriteBatch::new())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("user/1".to_owned())?, Some("alice@new".to_owned()));
+        assert_eq!(store.get("email/alice@old".to_owned())?, None);
+        assert_eq!(store.get("email/alice@new".to_owned())?, Some("user/1".to_owned()));
+        assert_eq!(store.get("never/set".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    drop(store);
+    // 默认的触发条件下记录太少，强制压缩一次
+    drop(KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compaction(CompactionTrigger::RecordCount(1)),
+    )?);
+    assert_eq!(count_files(temp_dir.path(), "sstable_")?, 1);
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key".to_owned(), "value".to_owned());
+    assert!(matches!(reader.write_batch(batch), Err(KvsError::ReadOnly)));
+
+    Ok(())
+}
+
+// A batch cut short by a crash is dropped as a whole
+#[test]
+fn torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    let mut batch = WriteBatch::new();
+    for i in 1..10 {
+        batch.set(format!("key{}", i), format!("value{}", i));
+    }
+    store.write_batch(batch)?;
+    drop(store);
+
+    // 只截掉最后一条记录的几个字节，前面的记录本身都是完整的
+    let log_path = temp_dir.path().join("log_0.txt");
+    let len = fs::metadata(&log_path)?.len();
+    fs::OpenOptions::new().write(true).open(&log_path)?.set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    for i in 1..10 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+
+    Ok(())
+}
+
+// Conditional writes only go through when the current value is the expected one
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_if_absent("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(store.set_if_absent("key1".to_owned(), "other".to_owned()), Err(KvsError::Conflict)));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let swap = |expected: Option<&str>, new: Option<&str>| {
+        store.compare_and_swap("key1".to_owned(), expected.map(str::to_owned), new.map(str::to_owned))
+    };
+    assert!(matches!(swap(Some("wrong"), Some("value2")), Err(KvsError::Conflict)));
+    assert!(matches!(swap(None, Some("value2")), Err(KvsError::Conflict)));
+    swap(Some("value1"), Some("value2"))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    assert!(matches!(store.remove_if_equals("key1".to_owned(), "value1".to_owned()), Err(KvsError::Conflict)));
+    store.remove_if_equals("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    swap(None, None)?;
+    assert!(matches!(swap(Some("value2"), None), Err(KvsError::Conflict)));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set_if_absent("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Read-modify-write loops built on compare_and_swap never lose an update
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for j in 0..50 {
+                    // 顺便写点别的 key，让条件写入和普通写入混在一起
+                    store.set(format!("noise{}", i), format!("{}", j)).unwrap();
+                    loop {
+                        let current = store.get("counter".to_owned()).unwrap().unwrap();
+                        let next = (current.parse::<u64>().unwrap() + 1).to_string();
+                        match store.compare_and_swap("counter".to_owned(), Some(current), Some(next)) {
+                            Ok(()) => break,
+                            Err(KvsError::Conflict) => continue,
+                            Err(e) => panic!("{}", e),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+
+    Ok(())
+}
+
+// Counters start from 0, persist, and refuse values that are not integers
+#[test]
+fn incr_by() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.incr_by("counter".to_owned(), 1)?, 1);
+    assert_eq!(store.incr_by("counter".to_owned(), 41)?, 42);
+    assert_eq!(store.incr_by("counter".to_owned(), -50)?, -8);
+    assert_eq!(store.get("counter".to_owned())?, Some("-8".to_owned()));
+
+    store.set("name".to_owned(), "alice".to_owned())?;
+    match store.incr_by("name".to_owned(), 1) {
+        Err(KvsError::NotANumber(value)) => assert_eq!(value, "alice"),
+        other => panic!("expected a non-numeric value, got {:?}", other),
+    }
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(store.incr_by("max".to_owned(), 1).is_err());
+    assert_eq!(store.get("max".to_owned())?, Some(i64::MAX.to_string()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.incr_by("counter".to_owned(), 8)?, 0);
+
+    // 并发的自增一个都不能丢
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    store.incr_by("counter".to_owned(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("800".to_owned()));
+
+    Ok(())
+}
+
+// Keys set with a TTL read as absent once it passes, also after a restart, and compaction drops them
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionTrigger::Disabled);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    store.set_with_ttl("session".to_owned(), "session-token".to_owned(), Duration::from_millis(300))?;
+    store.set_with_ttl("forever".to_owned(), "forever-token".to_owned(), Duration::from_secs(3600))?;
+    store.set("plain".to_owned(), "plain-value".to_owned())?;
+    assert_eq!(store.get("session".to_owned())?, Some("session-token".to_owned()));
+    assert_eq!(store.ttl("plain".to_owned())?, None);
+    let ttl = store.ttl("forever".to_owned())?.unwrap();
+    assert!(ttl > Duration::from_secs(3590) && ttl <= Duration::from_secs(3600), "ttl is {:?}", ttl);
+
+    // 再 set 一次就没有过期时间了
+    store.set_with_ttl("renewed".to_owned(), "old".to_owned(), Duration::from_millis(300))?;
+    store.set("renewed".to_owned(), "new".to_owned())?;
+
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(store.get("session".to_owned())?, None);
+    assert!(matches!(store.ttl("session".to_owned()), Err(KvsError::KeyNotFound)));
+    assert!(matches!(store.remove("session".to_owned()), Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get("renewed".to_owned())?, Some("new".to_owned()));
+    let keys: Vec<String> = store.scan(.., usize::MAX)?.map(|pair| pair.unwrap().0).collect();
+    assert_eq!(keys, ["forever", "plain", "renewed"]);
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("session".to_owned())?, None);
+    assert_eq!(store.get("forever".to_owned())?, Some("forever-token".to_owned()));
+    assert!(store.ttl("forever".to_owned())?.is_some());
+    drop(store);
+
+    // 打开的时候就会开始压缩，过期的值不会进 sstable
+    drop(KvStore::open_with(temp_dir.path(), options.compaction(CompactionTrigger::RecordCount(1)))?);
+    let sstable = fs::read(temp_dir.path().join("sstable_0.txt"))?;
+    let contains = |needle: &[u8]| sstable.windows(needle.len()).any(|window| window == needle);
+    assert!(!contains(b"session-token"));
+    assert!(contains(b"forever-token"));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("session".to_owned())?, None);
+    assert!(store.ttl("forever".to_owned())?.is_some());
+
+    Ok(())
+}
+
+// A snapshot keeps reading the values from when it was taken while writes and compactions go on
+#[test]
+fn snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionTrigger::RecordCount(50));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    for i in 0..20 {
+        store.set(format!("key{}", i), "old".to_owned())?;
+    }
+    store.set_with_ttl("session".to_owned(), "token".to_owned(), Duration::from_millis(300))?;
+    let snapshot = store.snapshot();
+
+    let mut batch = WriteBatch::new();
+    batch.set("key0".to_owned(), "new".to_owned()).remove("key1".to_owned()).set("fresh".to_owned(), "new".to_owned());
+    store.write_batch(batch)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(snapshot.get("key0".to_owned())?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("fresh".to_owned())?, None);
+    let later = store.snapshot();
+    assert_eq!(later.seq(), snapshot.seq() + 1);
+    assert_eq!(later.get("key1".to_owned())?, None);
+
+    // 一边不停地覆盖、压缩，一边读快照
+    for round in 0..20 {
+        for i in 0..20 {
+            store.set(format!("key{}", i), format!("round{}", round))?;
+        }
+        for i in 2..20 {
+            assert_eq!(snapshot.get(format!("key{}", i))?, Some("old".to_owned()));
+        }
+    }
+    thread::sleep(Duration::from_millis(400));
+    assert!(count_files(temp_dir.path(), "sstable_")? >= 1);
+    assert_eq!(snapshot.get("session".to_owned())?, Some("token".to_owned()));
+    assert_eq!(store.get("session".to_owned())?, None);
+    for i in 2..20 {
+        assert_eq!(snapshot.get(format!("key{}", i))?, Some("old".to_owned()));
+        assert_eq!(later.get(format!("key{}", i))?, Some("old".to_owned()));
+        assert_eq!(store.get(format!("key{}", i))?, Some("round19".to_owned()));
+    }
+    assert_eq!(later.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(later.get("fresh".to_owned())?, Some("new".to_owned()));
+
+    drop(snapshot);
+    drop(later);
+    assert_eq!(store.snapshot().get("key5".to_owned())?, Some("round19".to_owned()));
+    Ok(())
+}
+
+// Sequence numbers keep increasing across restarts, also once every record is in an sstable
+#[test]
+fn sequence_numbers_persist() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compact_keys(temp_dir.path(), 100, 10)?;
+    let store = KvStore::open(temp_dir.path())?;
+    let seq = store.snapshot().seq();
+    assert!(seq >= 100, "seq is {}", seq);
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.snapshot().seq(), seq + 1);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.snapshot().seq(), seq + 1);
+    Ok(())
+}
+
+fn history_of(store: &KvStore, key: &str) -> Result<Vec<(u64, Option<String>)>> {
+    Ok(store.history(key.to_owned())?.into_iter().map(|version| (version.seq, version.value)).collect())
+}
+
+// The last N versions of a key are kept through restarts, hint files and compaction
+#[test]
+fn version_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // 每次写入都切 log，打开的时候就是从 hint 读的
+    let options = KvStoreOptions::new()
+        .compaction(CompactionTrigger::Disabled)
+        .max_log_size(1)
+        .retention(VersionRetention::Versions(3));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for value in ["v1", "v2"] {
+        store.set("config".to_owned(), value.to_owned())?;
+    }
+    store.remove("config".to_owned())?;
+    for value in ["v3", "v4"] {
+        store.set("config".to_owned(), value.to_owned())?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+
+    let expected = vec![(5, Some("v4".to_owned())), (4, Some("v3".to_owned())), (3, None)];
+    assert_eq!(history_of(&store, "config")?, expected);
+    assert!(store.history("config".to_owned())?.iter().all(|version| version.written_at.is_some()));
+    assert_eq!(store.get_at("config".to_owned(), 4)?, Some("v3".to_owned()));
+    assert_eq!(store.get_at("config".to_owned(), 3)?, None);
+    assert_eq!(store.get_at("config".to_owned(), 6)?, Some("v4".to_owned()));
+    assert!(store.history("missing".to_owned())?.is_empty());
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(history_of(&store, "config")?, expected);
+    drop(store);
+
+    // 打开的时候就会开始压缩，压缩以后从 sstable 里读
+    let compacting = options.clone().compaction(CompactionTrigger::RecordCount(1));
+    drop(KvStore::open_with(temp_dir.path(), compacting)?);
+    assert_eq!(count_files(temp_dir.path(), "sstable_")?, 1);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(history_of(&store, "config")?, expected);
+    store.set("config".to_owned(), "v5".to_owned())?;
+    assert_eq!(
+        history_of(&store, "config")?,
+        vec![(7, Some("v5".to_owned())), (5, Some("v4".to_owned())), (4, Some("v3".to_owned()))]
+    );
+    assert_eq!(store.get_at("config".to_owned(), 6)?, Some("v4".to_owned()));
+    drop(store);
+
+    // 默认只留最新的
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(history_of(&store, "config")?, vec![(7, Some("v5".to_owned()))]);
+    assert_eq!(store.get_at("config".to_owned(), 5)?, None);
+    Ok(())
+}
+
+// With a retention window, versions written longer ago than the window are dropped
+#[test]
+fn version_retention_window() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction(CompactionTrigger::Disabled)
+        .retention(VersionRetention::Window(Duration::from_millis(300)));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "old1".to_owned())?;
+    store.set("key".to_owned(), "old2".to_owned())?;
+    thread::sleep(Duration::from_millis(400));
+    store.set("key".to_owned(), "new1".to_owned())?;
+    store.set("key".to_owned(), "new2".to_owned())?;
+    assert_eq!(history_of(&store, "key")?, vec![(4, Some("new2".to_owned())), (3, Some("new1".to_owned()))]);
+
+    assert_eq!("versions:5".parse::<VersionRetention>()?, VersionRetention::Versions(5));
+    assert_eq!("window:60".parse::<VersionRetention>()?, VersionRetention::Window(Duration::from_secs(60)));
+    assert!("versions:0".parse::<VersionRetention>().is_err());
+    Ok(())
+}
+
+#[test]
+fn transaction_sees_own_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+
+    let seen = store.transaction(|txn| {
+        txn.set("a".to_owned(), "10".to_owned());
+        txn.remove("b".to_owned());
+        txn.set("c".to_owned(), "3".to_owned());
+        Ok((txn.get("a".to_owned())?, txn.get("b".to_owned())?, txn.get("c".to_owned())?))
+    })?;
+    assert_eq!(seen, (Some("10".to_owned()), None, Some("3".to_owned())));
+    assert_eq!(store.get("a".to_owned())?, Some("10".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert_eq!(store.get("c".to_owned())?, Some("3".to_owned()));
+
+    // 闭包返回错误的话什么都不写
+    let result: Result<()> = store.transaction(|txn| {
+        txn.set("a".to_owned(), "lost".to_owned());
+        Err(KvsError::KeyNotFound)
+    });
+    assert!(matches!(result, Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get("a".to_owned())?, Some("10".to_owned()));
+
+    // 事务的写入是一条记录，重新打开以后也是一起的
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("10".to_owned()));
+    assert_eq!(store.get("c".to_owned())?, Some("3".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn transaction_retries_on_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    // 第一次执行的时候别人改了读过的 key，要重来一次
+    let mut attempts = 0;
+    store.transaction(|txn| {
+        attempts += 1;
+        let value: i64 = txn.get("counter".to_owned())?.unwrap().parse().unwrap();
+        if attempts == 1 {
+            store.set("counter".to_owned(), "100".to_owned())?;
+        }
+        txn.set("counter".to_owned(), (value + 1).to_string());
+        Ok(())
+    })?;
+    assert_eq!(attempts, 2);
+    assert_eq!(store.get("counter".to_owned())?, Some("101".to_owned()));
+
+    // 写过但没读过的 key 被别人改了不算冲突
+    let mut attempts = 0;
+    store.transaction(|txn| {
+        attempts += 1;
+        store.set("other".to_owned(), "theirs".to_owned())?;
+        txn.set("other".to_owned(), "ours".to_owned());
+        Ok(())
+    })?;
+    assert_eq!(attempts, 1);
+    assert_eq!(store.get("other".to_owned())?, Some("ours".to_owned()));
+
+    // 一直冲突的话最后放弃
+    let result = store.transaction(|txn| {
+        txn.get("counter".to_owned())?;
+        store.set("counter".to_owned(), "0".to_owned())?;
+        Ok(())
+    });
+    assert!(matches!(result, Err(KvsError::Conflict)));
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionTrigger::RecordCount(100));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..4 {
+        store.set(format!("account{}", i), "1000".to_owned())?;
+    }
+
+    // 几个线程不停地在账户之间转账，总数不能变
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    let from = format!("account{}", (t + i) % 4);
+                    let to = format!("account{}", (t + i + 1) % 4);
+                    store.transaction(|txn| {
+                        let a: i64 = txn.get(from.clone())?.unwrap().parse().unwrap();
+                        let b: i64 = txn.get(to.clone())?.unwrap().parse().unwrap();
+                        txn.set(from.clone(), (a - 7).to_string());
+                        txn.set(to.clone(), (b + 7).to_string());
+                        Ok(())
+                    })?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let total: i64 = (0..4)
+        .map(|i| store.get(format!("account{}", i)).unwrap().unwrap().parse::<i64>().unwrap())
+        .sum();
+    assert_eq!(total, 4000);
+
+    Ok(())
+}
+
+#[test]
+fn checkpoint_while_writing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = backup_dir.path().join("backup");
+    let options = KvStoreOptions::new().compaction(CompactionTrigger::RecordCount(200));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    // 等后台压缩做完第一轮：第一个 log 删掉的时候新的 sstable 已经在 manifest 里了
+    for _ in 0..500 {
+        if !temp_dir.path().join("log_0.txt").exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(!temp_dir.path().join("log_0.txt").exists());
+
+    // 备份的时候另一个线程一直在写，压缩也一直在换文件
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 0..2000 {
+                store.set(format!("late{}", i), "late".to_owned())?;
+            }
+            Ok(())
+        })
+    };
+    store.checkpoint(&dest)?;
+    for round in 0..10 {
+        let dest = backup_dir.path().join(format!("round{}", round));
+        store.checkpoint(&dest)?;
+        let backup = KvStore::open_read_only(&dest)?;
+        assert_eq!(backup.get("key999".to_owned())?, Some("value999".to_owned()));
+    }
+    writer.join().unwrap()?;
+    assert!(count_files(&dest, "sstable_")? >= 1);
+    assert!(matches!(store.checkpoint(&dest), Err(KvsError::InvalidArgument(_))));
+
+    let backup = KvStore::open(&dest)?;
+    assert_eq!(backup.get("key0".to_owned())?, None);
+    for i in 1..1000 {
+        assert_eq!(backup.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    // 备份里的写入是原来的一个前缀
+    let late: Vec<_> = (0..2000).map(|i| backup.get(format!("late{}", i)).unwrap().is_some()).collect();
+    assert!(late.windows(2).all(|pair| pair[0] >= pair[1]));
+
+    // 备份和原来的 store 各写各的
+    backup.set("key1".to_owned(), "backup".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("late1999".to_owned())?, Some("late".to_owned()));
+    drop(backup);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let backup = KvStore::open(&dest)?;
+    assert_eq!(backup.get("key1".to_owned())?, Some("backup".to_owned()));
+
+    Ok(())
+}
+
+// Checkpoints racing for one directory: exactly one wins, and the loser leaves the winner's copy alone
+#[test]
+fn concurrent_checkpoints_to_one_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = backup_dir.path().join("backup");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let barrier = Arc::new(Barrier::new(4));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let dest = dest.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                store.checkpoint(&dest)
+            })
+        })
+        .collect();
+    let results: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+    assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+    assert!(results
+        .iter()
+        .all(|result| matches!(result, Ok(()) | Err(KvsError::InvalidArgument(_)))));
+
+    // 只剩下做好的那一份，没有临时目录
+    assert_eq!(fs::read_dir(backup_dir.path())?.count(), 1);
+    let backup = KvStore::open(&dest)?;
+    for i in 0..100 {
+        assert_eq!(backup.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_ok());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        // 后台压缩会删文件，列出来以后不见了的不算
+        let len: walkdir::Result<u64> = entries
+            .map(|res| match res.and_then(|entry| entry.metadata()) {
+                Ok(metadata) => Ok(metadata.len()),
+                Err(e) if e.io_error().is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) => Ok(0),
+                Err(e) => Err(e),
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+
+        drop(store);
+        // reopen and check content
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// The in-memory index stays valid across compactions without reopening
+#[test]
+fn read_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for i in 0..3000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key2500".to_owned())?;
+    for i in 0..3000 {
+        let expected = if i == 2500 { None } else { Some(format!("value{}", i)) };
+        assert_eq!(store.get(format!("key{}", i))?, expected);
+    }
+
+    Ok(())
+}
+
+// Removing a key that was compacted into an sstable must not bring it back
+#[test]
+fn remove_compacted_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key".to_owned(), "value".to_owned())?;
+    for i in 0..2500 {
+        store.set(format!("filler{}", i), format!("value{}", i))?;
+    }
+    store.remove("key".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert!(store.remove("key".to_owned()).is_err());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(store.get("filler0".to_owned())?, Some("value0".to_owned()));
+
+    Ok(())
+}
+
+// Keys overwritten across many compactions read back their newest value
+#[test]
+fn overwrite_across_compactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("removed".to_owned(), "value".to_owned())?;
+    for round in 0..15 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", round))?;
+        }
+        if round == 5 {
+            store.remove("removed".to_owned())?;
+        }
+        for filler_id in 0..2000 {
+            store.set(format!("filler{}", filler_id), format!("{}", round))?;
+        }
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("14".to_owned()));
+        }
+        assert_eq!(store.get("filler0".to_owned())?, Some("14".to_owned()));
+        assert_eq!(store.get("removed".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// sstable_10 is newer than sstable_2 even though it sorts first as a string
+#[test]
+fn sstable_generations_sort_numerically() -> Result<()> {
+    // Build a store whose only sstable holds `key` -> `value`
+    let compacted = |value: &str| -> Result<TempDir> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key".to_owned(), value.to_owned())?;
+        for i in 0..2001 {
+            store.set(format!("filler{}", i), "value".to_owned())?;
+        }
+        drop(store);
+        Ok(temp_dir)
+    };
+    let old_dir = compacted("old")?;
+    let new_dir = compacted("new")?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::copy(old_dir.path().join("sstable_0.txt"), temp_dir.path().join("sstable_2.txt"))?;
+    fs::copy(new_dir.path().join("sstable_0.txt"), temp_dir.path().join("sstable_10.txt"))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("new".to_owned()));
+
+    Ok(())
+}
+
+// Compaction merges everything, so overwritten and removed keys stop taking space
+#[test]
+fn compaction_reclaims_space() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for round in 0..30 {
+        for key_id in 0..100 {
+            store.set(format!("hot{}", key_id), format!("{}", round))?;
+        }
+        for key_id in 0..500 {
+            store.set(format!("temp{}_{}", round, key_id), "value".to_owned())?;
+            store.remove(format!("temp{}_{}", round, key_id))?;
+        }
+    }
+
+    // Dropping the store waits for a compaction still running in the background
+    drop(store);
+
+    let mut sstable_count = 0;
+    let mut dir_size = 0;
+    for entry in fs::read_dir(temp_dir.path())? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with("sstable") {
+            sstable_count += 1;
+        }
+        dir_size += entry.metadata()?.len();
+    }
+    assert_eq!(sstable_count, 1);
+    // About 33k records were written, two compactions' worth stay on disk at most
+    assert!(dir_size < 4000 * 40, "directory takes {} bytes", dir_size);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("hot{}", key_id))?, Some("29".to_owned()));
+    }
+    assert_eq!(store.get("temp0_0".to_owned())?, None);
+    assert_eq!(store.get("temp29_499".to_owned())?, None);
+
+    Ok(())
+}
+
+fn count_files(dir: &Path, prefix: &str) -> Result<usize> {
+    let mut count = 0;
+    for entry in fs::read_dir(dir)? {
+        if entry?.file_name().to_string_lossy().starts_with(prefix) {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// The compaction trigger is taken from the options
+#[test]
+fn configurable_compaction_trigger() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionTrigger::Disabled);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..3000 {
+        store.set("key".to_owned(), format!("{}", i))?;
+    }
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    drop(store);
+    assert_eq!(count_files(temp_dir.path(), "sstable_")?, 0);
+
+    let options = KvStoreOptions::new().compaction(CompactionTrigger::RecordCount(100));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "last".to_owned())?;
+    drop(store);
+    assert_eq!(count_files(temp_dir.path(), "sstable_")?, 1);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("last".to_owned()));
+
+    Ok(())
+}
+
+// Compacted keys are spread over many sorted blocks; lookups and scans find each one
+#[test]
+fn sstable_spans_many_blocks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionTrigger::Disabled);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    // 乱序写入，压缩后应当排好序
+    for i in 0..2000 {
+        let n = i * 7919 % 2000;
+        store.set(format!("key{:04}", n), format!("value{:04}{}", n, "x".repeat(40)))?;
+    }
+    drop(store);
+
+    let options = KvStoreOptions::new().compaction(CompactionTrigger::RecordCount(100));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key0000".to_owned(), "first".to_owned())?;
+    drop(store);
+    assert_eq!(count_files(temp_dir.path(), "sstable_")?, 1);
+
+    let sstable = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.file_name().to_string_lossy().starts_with("sstable_"))
+        .expect("sstable not found");
+    assert!(fs::read(sstable.path())?.ends_with(b"KVSF"));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0000".to_owned())?, Some("first".to_owned()));
+    for n in 1..2000 {
+        let value = format!("value{:04}{}", n, "x".repeat(40));
+        assert_eq!(store.get(format!("key{:04}", n))?, Some(value));
+    }
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(store.get("key0500a".to_owned())?, None);
+    assert_eq!(store.get("key2000".to_owned())?, None);
+
+    let keys = collect(store.scan("key0995".to_owned().."key1005".to_owned(), usize::MAX)?)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect::<Vec<_>>();
+    let expected = (995..1005).map(|n| format!("key{:04}", n)).collect::<Vec<_>>();
+    assert_eq!(keys, expected);
+
+    Ok(())
+}
+
+// Writes `count` keys and compacts them into a single sstable with the given filter size
+fn compact_keys(dir: &Path, count: usize, bits_per_key: u32) -> Result<()> {
+    let options = KvStoreOptions::new()
+        .compaction(CompactionTrigger::Disabled)
+        .bloom_bits_per_key(bits_per_key);
+    let store = KvStore::open_with(dir, options.clone())?;
+    for i in 0..count {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    // 打开的时候就会开始压缩
+    drop(KvStore::open_with(dir, options.compaction(CompactionTrigger::RecordCount(1)))?);
+    assert_eq!(count_files(dir, "sstable_")?, 1);
     Ok(())
 }
 
+// Lookups for absent keys are answered by the persisted Bloom filter without reading the sstable
 #[test]
-fn remove_key() -> Result<()> {
+fn bloom_filter_skips_absent_keys() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compact_keys(temp_dir.path(), 1000, 10)?;
+
     let store = KvStore::open(temp_dir.path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("absent{}", i))?, None);
+    }
+    let stats = store.bloom_stats();
+    assert_eq!(stats.checks, 1000);
+    assert!(stats.reads_avoided >= 950, "only {} reads avoided", stats.reads_avoided);
+    assert_eq!(stats.false_positives, stats.checks - stats.reads_avoided);
+
+    // 存在的 key 一个都不能被过滤掉
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    let after = store.clone().bloom_stats();
+    assert_eq!(after.checks, 2000);
+    assert_eq!(after.reads_avoided, stats.reads_avoided);
+    assert_eq!(after.false_positives, stats.false_positives);
+
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// With filters turned off every lookup reads the sstable
 #[test]
-fn compaction() -> Result<()> {
+fn bloom_filter_disabled() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compact_keys(temp_dir.path(), 100, 0)?;
+
     let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("absent".to_owned())?, None);
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+    assert_eq!(store.bloom_stats(), BloomStats::default());
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
-    };
+    Ok(())
+}
 
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
-        }
+// Compaction by stale ratio only kicks in once enough of the log is overwritten
+#[test]
+fn compaction_by_stale_ratio() -> Result<()> {
+    let options = KvStoreOptions::new().compaction(CompactionTrigger::StaleRatio {
+        ratio: 0.5,
+        min_bytes: 4096,
+    });
 
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..3000 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    drop(store);
+    assert_eq!(count_files(temp_dir.path(), "sstable_")?, 0);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..6000 {
+        store.set(format!("key{}", i % 10), format!("{}", i))?;
+    }
+    drop(store);
+    assert_eq!(count_files(temp_dir.path(), "sstable_")?, 1);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key9".to_owned())?, Some("5999".to_owned()));
+    assert_eq!(store.get("key2999".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// A full log is sealed and writes continue in a new one
+#[test]
+fn roll_log_at_max_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction(CompactionTrigger::Disabled)
+        .max_log_size(1024)
+        .sync(SyncPolicy::Always);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(count_files(temp_dir.path(), "log_")? > 1);
+    for entry in fs::read_dir(temp_dir.path())? {
+        // A record never straddles two files, so a log overshoots by at most one record
+        let entry = entry?;
+        if entry.path().extension().is_some_and(|ext| ext == "txt") {
+            assert!(entry.metadata()?.len() < 1024 + 64);
         }
-        // Compaction triggered
+    }
 
-        drop(store);
-        // reopen and check content
-        let store = KvStore::open(temp_dir.path())?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+fn hint_files(dir: &Path) -> Result<Vec<std::path::PathBuf>> {
+    let mut hints = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "hint") {
+            hints.push(path);
         }
-        return Ok(());
     }
+    Ok(hints)
+}
 
-    panic!("No compaction detected");
+fn check_hinted_store(dir: &Path, options: KvStoreOptions) -> Result<()> {
+    let store = KvStore::open_with(dir, options)?;
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    for i in 10..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", 200 + i)));
+    }
+    Ok(())
+}
+
+// Sealed logs get a hint file, and open rebuilds their index from it instead of the log
+#[test]
+fn open_from_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction(CompactionTrigger::Disabled)
+        .max_log_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..300 {
+        store.set(format!("key{}", i % 100), format!("value{}", i))?;
+    }
+    for i in 0..10 {
+        store.remove(format!("key{}", i))?;
+    }
+    drop(store);
+    assert!(hint_files(temp_dir.path())?.len() > 1);
+    check_hinted_store(temp_dir.path(), options.clone())?;
+
+    // key0 最早的那条记录早就被覆盖了，有 hint 的时候打开不会去读它
+    let log = temp_dir.path().join("log_0.txt");
+    let original = fs::read(&log)?;
+    let mut bytes = original.clone();
+    bytes[8 + 17 + 4 + 1] ^= 0xff;
+    fs::write(&log, bytes)?;
+    check_hinted_store(temp_dir.path(), options.clone())?;
+
+    // 坏掉的 hint 会被忽略，回头去读 log
+    let hint = temp_dir.path().join("log_0.hint");
+    fs::remove_file(&hint)?;
+    match KvStore::open_with(temp_dir.path(), options.clone()) {
+        Err(KvsError::Corruption { offset: 8, .. }) => {}
+        other => panic!("expected corruption, got {:?}", other.map(|_| ())),
+    }
+    for path in hint_files(temp_dir.path())? {
+        let mut bytes = fs::read(&path)?;
+        bytes[20] ^= 0xff;
+        fs::write(&path, bytes)?;
+    }
+    fs::write(&log, original)?;
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key50".to_owned())?, Some("value250".to_owned()));
+
+    Ok(())
+}
+
+// Files the manifest does not list are leftovers of an interrupted compaction and are removed
+#[test]
+fn manifest_ignores_stray_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("MANIFEST").exists());
+
+    // 另一个 store 写出来的 log 和 sstable，冒充压缩到一半留下的文件
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other = KvStore::open(other_dir.path())?;
+    other.set("key1".to_owned(), "ghost".to_owned())?;
+    drop(other);
+    fs::copy(other_dir.path().join("log_0.txt"), temp_dir.path().join("log_7.txt"))?;
+    fs::copy(other_dir.path().join("log_0.txt"), temp_dir.path().join("sstable_3.txt"))?;
+    fs::write(temp_dir.path().join("sstable_4.tmp"), "half written")?;
+    fs::write(temp_dir.path().join("log_2.upgrading"), "half written")?;
+    fs::write(temp_dir.path().join("log_7.hint"), "stale")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let mut names: Vec<String> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().to_string()))
+        .collect::<std::io::Result<_>>()?;
+    names.sort();
+    assert_eq!(names, vec!["LOCK", "MANIFEST", "log_0.txt"]);
+
+    Ok(())
+}
+
+// A file the manifest lists must not silently disappear
+#[test]
+fn manifest_reports_missing_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionTrigger::RecordCount(10));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    drop(store);
+    assert_eq!(count_files(temp_dir.path(), "sstable_")?, 1);
+
+    fs::remove_file(temp_dir.path().join("sstable_0.txt"))?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::MissingFile(file)) => assert!(file.ends_with("sstable_0.txt")),
+        other => panic!("expected a missing file, got {:?}", other.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// Only one writer at a time may open a directory; read-only opens can join it
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Locked(_)) => {}
+        other => panic!("expected the directory to be locked, got {:?}", other.map(|_| ())),
+    }
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // 所有 clone 都 drop 了才放锁
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locked(_))));
+    drop(clone);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Read-only opens racing a writer that keeps compacting and rolling logs still succeed
+#[test]
+fn open_read_only_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction(CompactionTrigger::RecordCount(50))
+        .max_log_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("stable".to_owned(), "value".to_owned())?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 0..5000 {
+                store.set(format!("key{}", i % 100), format!("value{}", i))?;
+            }
+            Ok(())
+        })
+    };
+    while !writer.is_finished() {
+        let reader = KvStore::open_read_only(temp_dir.path())?;
+        assert_eq!(reader.get("stable".to_owned())?, Some("value".to_owned()));
+    }
+    writer.join().unwrap()?;
+
+    Ok(())
+}
+
+// A read-only store serves reads, rejects writes and leaves the files alone
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("log_0.txt");
+    let len = fs::metadata(&log_path)?.len();
+    fs::OpenOptions::new().write(true).open(&log_path)?.set_len(len - 3)?;
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().read_only(true))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(matches!(
+        store.set("key3".to_owned(), "value3".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(store.remove("key1".to_owned()), Err(KvsError::ReadOnly)));
+    drop(store);
+    assert_eq!(fs::metadata(&log_path)?.len(), len - 3);
+
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_with(&missing, KvStoreOptions::new().read_only(true)).is_err());
+    assert!(!missing.exists());
+
+    Ok(())
 }
 
 #[test]
@@ -160,6 +1555,51 @@ fn concurrent_set() -> Result<()> {
     Ok(())
 }
 
+// Writers keep going while compactions run in the background
+#[test]
+fn concurrent_set_with_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for round in 0..5 {
+                for key_id in 0..500 {
+                    store
+                        .set(format!("key{}_{}", thread_id, key_id), format!("{}", round))
+                        .unwrap();
+                    assert_eq!(
+                        store.get(format!("key{}_{}", thread_id, key_id)).unwrap(),
+                        Some(format!("{}", round))
+                    );
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for thread_id in 0..8 {
+        for key_id in 0..500 {
+            assert_eq!(store.get(format!("key{}_{}", thread_id, key_id))?, Some("4".to_owned()));
+        }
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for key_id in 0..500 {
+            assert_eq!(store.get(format!("key{}_{}", thread_id, key_id))?, Some("4".to_owned()));
+        }
+    }
+
+    Ok(())
+}
+
 #[test]
 fn concurrent_get() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -211,3 +1651,212 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Readers keep finding every key while writes roll logs and compactions delete files
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionTrigger::RecordCount(200));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..500 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 0..5000 {
+                store.set(format!("other{}", i % 300), format!("{}", i)).unwrap();
+            }
+        })
+    };
+    let readers: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..2000 {
+                    let key_id = (i * 7 + thread_id) % 500;
+                    assert_eq!(
+                        store.get(format!("key{}", key_id)).unwrap(),
+                        Some(format!("value{}", key_id))
+                    );
+                }
+            })
+        })
+        .collect();
+
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    assert_eq!(store.get("other199".to_owned())?, Some("4999".to_owned()));
+
+    Ok(())
+}
+
+// History reads stay consistent while compaction moves versions from the logs into sstables
+#[test]
+fn history_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction(CompactionTrigger::RecordCount(200))
+        .retention(VersionRetention::Versions(3));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for version in 0..3 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("{}", version))?;
+        }
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 0..5000 {
+                store.set(format!("other{}", i % 300), format!("{}", i)).unwrap();
+            }
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..1000 {
+                    let key = format!("key{}", (i * 7 + thread_id) % 100);
+                    let values: Vec<_> = history_of(&store, &key).unwrap().into_iter().map(|(_, value)| value).collect();
+                    assert_eq!(values, vec![Some("2".to_owned()), Some("1".to_owned()), Some("0".to_owned())]);
+                }
+            })
+        })
+        .collect();
+
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    assert!(count_files(temp_dir.path(), "sstable_")? >= 1);
+
+    Ok(())
+}
+
+fn collect(pairs: kvs::ScanIter) -> Result<Vec<(String, String)>> {
+    pairs.collect()
+}
+
+fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+    expected
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+// Scans merge the logs and every sstable, newest value first, in key order
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..2500 {
+        store.set(format!("key{:04}", i), "old".to_owned())?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0001".to_owned(), "new".to_owned())?;
+    store.remove("key0002".to_owned())?;
+    store.set("key0003a".to_owned(), "added".to_owned())?;
+
+    assert_eq!(
+        collect(store.scan("key0000".to_owned().."key0005".to_owned(), 100)?)?,
+        pairs(&[
+            ("key0000", "old"),
+            ("key0001", "new"),
+            ("key0003", "old"),
+            ("key0003a", "added"),
+            ("key0004", "old"),
+        ])
+    );
+    assert_eq!(
+        collect(store.scan("key2498".to_owned().., 10)?)?,
+        pairs(&[("key2498", "old"), ("key2499", "old")])
+    );
+    assert_eq!(collect(store.scan(.., 3)?)?.len(), 3);
+    assert_eq!(collect(store.scan(.., usize::MAX)?)?.len(), 2500);
+    assert!(collect(store.scan("key9".to_owned().."key0".to_owned(), 10)?)?.is_empty());
+    assert!(collect(store.scan(.., 0)?)?.is_empty());
+
+    Ok(())
+}
+
+// Long scans read the logs a chunk at a time and still see every key once, in order
+#[test]
+fn scan_across_chunks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionTrigger::Disabled);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..2000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    for i in (0..2000).step_by(3) {
+        store.remove(format!("key{:04}", i))?;
+    }
+
+    let expected: Vec<_> = (0..2000)
+        .filter(|i| i % 3 != 0)
+        .map(|i| (format!("key{:04}", i), format!("value{}", i)))
+        .collect();
+    assert_eq!(collect(store.scan(.., usize::MAX)?)?, expected);
+    assert_eq!(collect(store.scan(.., 300)?)?, expected[..300]);
+    assert_eq!(
+        collect(store.scan("key0500".to_owned()..="key1500".to_owned(), usize::MAX)?)?,
+        expected.iter().filter(|(key, _)| ("key0500".."key1501").contains(&key.as_str())).cloned().collect::<Vec<_>>()
+    );
+
+    // 扫到一半接着写，已经扫过的段不受影响，后面的段看得到新写的
+    let mut scan = store.scan(.., usize::MAX)?;
+    let first = scan.next().unwrap()?;
+    store.set("key1999a".to_owned(), "late".to_owned())?;
+    let rest = collect(scan)?;
+    assert_eq!(first, expected[0]);
+    assert_eq!(rest.len(), expected.len());
+    assert_eq!(rest.last().unwrap(), &("key1999a".to_owned(), "late".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user/123/profile".to_owned(), "p123".to_owned())?;
+    store.set("user/123/settings".to_owned(), "s123".to_owned())?;
+    store.set("user/124/profile".to_owned(), "p124".to_owned())?;
+    store.set("user/12".to_owned(), "u12".to_owned())?;
+    store.set("users".to_owned(), "all".to_owned())?;
+    store.set("team/1".to_owned(), "t1".to_owned())?;
+    store.remove("user/124/profile".to_owned())?;
+
+    assert_eq!(
+        collect(store.scan_prefix("user/12")?)?,
+        pairs(&[
+            ("user/12", "u12"),
+            ("user/123/profile", "p123"),
+            ("user/123/settings", "s123"),
+        ])
+    );
+    assert_eq!(
+        collect(store.scan_prefix("user/123/")?)?,
+        pairs(&[("user/123/profile", "p123"), ("user/123/settings", "s123")])
+    );
+    assert!(collect(store.scan_prefix("nobody/")?)?.is_empty());
+    assert_eq!(collect(store.scan_prefix("")?)?.len(), 5);
+
+    // 前缀的最后一个字符已经是最大的 char
+    store.set("z\u{10FFFF}".to_owned(), "max".to_owned())?;
+    store.set("z\u{10FFFF}a".to_owned(), "after max".to_owned())?;
+    store.set("{".to_owned(), "past z".to_owned())?;
+    assert_eq!(
+        collect(store.scan_prefix("z\u{10FFFF}")?)?,
+        pairs(&[("z\u{10FFFF}", "max"), ("z\u{10FFFF}a", "after max")])
+    );
+
+    Ok(())
+}